@@ -0,0 +1,190 @@
+use parking_lot::{Mutex, RwLock};
+use serde_json::Value;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, SyncSender, TrySendError},
+    thread::JoinHandle,
+};
+
+// records waiting for the writer, more are dropped
+const QUEUE_MAX: usize = 4096;
+
+lazy_static::lazy_static! {
+    // opened before changing uid, until start() hands it to the writer
+    static ref ACCESS_LOG: Mutex<Option<RotatingFile>> = Mutex::new(None);
+    static ref SENDER: RwLock<Option<SyncSender<String>>> = RwLock::new(None);
+    static ref WRITER: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+}
+
+// append only file, moved to "[path].1" once it grows over max bytes
+pub(crate) struct RotatingFile {
+    path: PathBuf,
+    max: u64,
+    file: File,
+    len: u64,
+}
+
+impl RotatingFile {
+    pub(crate) fn open(path: &str, max: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let len = file.metadata()?.len();
+
+        Ok(RotatingFile {
+            path: PathBuf::from(path),
+            max,
+            file,
+            len,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut backup = self.path.clone().into_os_string();
+        backup.push(".1");
+        std::fs::rename(&self.path, backup)?;
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.len = 0;
+
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.len != 0 && self.len + buf.len() as u64 > self.max {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.len += n as u64;
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+// setting.access_log, max in KB
+pub(crate) fn init(path: &str, max: u64) -> io::Result<()> {
+    if !path.is_empty() {
+        *ACCESS_LOG.lock() = Some(RotatingFile::open(path, max * 1024)?);
+    }
+
+    Ok(())
+}
+
+// renaming the file and creating it again needs a directory writable by the running uid,
+// and owning the files in a sticky one like /tmp. run after changing uid
+#[cfg(not(target_os = "windows"))]
+pub(crate) fn check_rotate(path: &str) -> io::Result<()> {
+    use std::os::unix::{ffi::OsStrExt, fs::MetadataExt};
+
+    let path = Path::new(path);
+    let dir = match path.parent() {
+        Some(x) if !x.as_os_str().is_empty() => x,
+        _ => Path::new("."),
+    };
+    let denied = |reason: String| {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} can't be rotated, {}", path.display(), reason),
+        )
+    };
+
+    let c_dir = std::ffi::CString::new(dir.as_os_str().as_bytes())?;
+    let uid = unsafe { libc::geteuid() };
+    if unsafe { libc::access(c_dir.as_ptr(), libc::W_OK | libc::X_OK) } != 0 {
+        return Err(denied(format!(
+            "{} not writable by uid {}",
+            dir.display(),
+            uid
+        )));
+    }
+    if std::fs::metadata(dir)?.mode() & 0o1000 != 0 {
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(".1");
+        for file in [path, Path::new(&backup)] {
+            if matches!(std::fs::metadata(file), Ok(x) if x.uid() != uid) {
+                return Err(denied(format!(
+                    "{} not owned by uid {}",
+                    file.display(),
+                    uid
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+pub(crate) fn check_rotate(_path: &str) -> io::Result<()> {
+    Ok(())
+}
+
+// the writer thread, after daemon() as threads don't survive a fork
+pub(crate) fn start() {
+    let mut file = match ACCESS_LOG.lock().take() {
+        Some(o) => o,
+        None => return,
+    };
+    let (sender, receiver) = mpsc::sync_channel::<String>(QUEUE_MAX);
+    *SENDER.write() = Some(sender);
+
+    *WRITER.lock() = Some(std::thread::spawn(move || {
+        for line in receiver {
+            if let Err(e) = file.write_all(line.as_bytes()) {
+                log::warn!("access_log {}", e);
+            }
+        }
+    }));
+}
+
+// records already sent are written before exit
+pub(crate) fn stop() {
+    SENDER.write().take();
+    if let Some(writer) = WRITER.lock().take() {
+        let _ = writer.join();
+    }
+}
+
+// one json record per line, skipped when access_log is unset.
+// the flow only queues it, the writer thread does the io
+pub(crate) fn write(record: impl FnOnce() -> Value) {
+    let sender = SENDER.read();
+    let sender = match sender.as_ref() {
+        Some(o) => o,
+        None => return,
+    };
+    let mut line = record().to_string();
+    line.push('\n');
+    match sender.try_send(line) {
+        Ok(()) | Err(TrySendError::Disconnected(_)) => {}
+        Err(TrySendError::Full(_)) => log::warn!("access_log queue full, record dropped"),
+    }
+}
+
+#[test]
+fn test_rotating_file() {
+    let dir = crate::misc::TestDir::new("rotating_file");
+    let path = dir.0.join("access.log");
+    let path = path.to_str().unwrap();
+
+    let mut file = RotatingFile::open(path, 12).unwrap();
+    file.write_all(b"12345\n").unwrap();
+    file.write_all(b"67890\n").unwrap();
+    file.write_all(b"abc\n").unwrap();
+    drop(file);
+
+    assert_eq!(
+        std::fs::read_to_string(format!("{}.1", path)).unwrap(),
+        "12345\n67890\n"
+    );
+    assert_eq!(std::fs::read_to_string(path).unwrap(), "abc\n");
+}
@@ -0,0 +1,295 @@
+use crate::{
+    config,
+    misc::{civil_from_days, split_addr_str},
+};
+use dashmap::DashMap;
+use log::*;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    net::IpAddr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// (hour, in, out, source)
+type Key = (u64, String, String, String);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static::lazy_static! {
+    // not yet written to setting.accounting
+    static ref PENDING: DashMap<Key, Totals> = DashMap::new();
+    static ref FILE: Mutex<Option<File>> = Mutex::new(None);
+}
+
+#[derive(Default, Clone, Copy)]
+struct Totals {
+    up: u64,
+    down: u64,
+    flows: u64,
+}
+
+// one line of setting.accounting, lines with the same key add up
+#[derive(Serialize, Deserialize)]
+struct Bucket {
+    // unix seconds of the hour, UTC
+    hour: u64,
+    #[serde(rename = "in")]
+    r#in: String,
+    out: String,
+    // "" for an out which routes again
+    source: String,
+    up: u64,
+    down: u64,
+    flows: u64,
+}
+
+// compact setting.accounting and keep it open, before changing uid
+pub(crate) fn init(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if path.is_empty() {
+        return Ok(());
+    }
+
+    if std::path::Path::new(path).exists() {
+        let buckets = read(path)?;
+        let temp = format!("{}.tmp", path);
+        let mut file = File::create(&temp)?;
+        for (key, totals) in buckets {
+            writeln!(file, "{}", bucket_line(key, totals))?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&temp, path)?;
+    }
+
+    *FILE.lock() = Some(OpenOptions::new().create(true).append(true).open(path)?);
+    ENABLED.store(true, Ordering::Relaxed);
+
+    Ok(())
+}
+
+// counted into the bucket of the current hour
+pub(crate) fn add(tag: &str, out: &str, saddr: &str, up: u64, down: u64, flows: u64) {
+    if !ENABLED.load(Ordering::Relaxed) || (up == 0 && down == 0 && flows == 0) {
+        return;
+    }
+
+    let source = match split_addr_str(saddr) {
+        Ok((ip, _)) if ip.parse::<IpAddr>().is_ok() => ip,
+        _ => String::new(),
+    };
+    let key = (
+        unix_secs(SystemTime::now()) / 3600 * 3600,
+        tag.to_string(),
+        out.to_string(),
+        source,
+    );
+
+    let mut totals = PENDING.entry(key).or_default();
+    totals.up += up;
+    totals.down += down;
+    totals.flows += flows;
+}
+
+// bytes of live flows are counted every minute, not only when they end
+pub(crate) async fn flush_every_minute() {
+    loop {
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        flush();
+    }
+}
+
+pub(crate) fn flush() {
+    let mut file = FILE.lock();
+    let file = match file.as_mut() {
+        Some(s) => s,
+        None => return,
+    };
+    crate::route::account_flows();
+
+    let keys: Vec<Key> = PENDING.iter().map(|x| x.key().clone()).collect();
+    let mut lines = String::new();
+    for key in keys {
+        if let Some((key, totals)) = PENDING.remove(&key) {
+            lines.push_str(&bucket_line(key, totals));
+            lines.push('\n');
+        }
+    }
+    if let Err(e) = file.write_all(lines.as_bytes()) {
+        warn!("accounting {}", e);
+    }
+}
+
+fn bucket_line((hour, r#in, out, source): Key, totals: Totals) -> String {
+    serde_json::to_string(&Bucket {
+        hour,
+        r#in,
+        out,
+        source,
+        up: totals.up,
+        down: totals.down,
+        flows: totals.flows,
+    })
+    .unwrap_or_default()
+}
+
+// merged by key, invalid lines are skipped
+fn read(path: &str) -> Result<BTreeMap<Key, Totals>, Box<dyn std::error::Error>> {
+    let mut buckets: BTreeMap<Key, Totals> = BTreeMap::new();
+    for (index, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let bucket: Bucket = match serde_json::from_str(&line?) {
+            Ok(o) => o,
+            Err(e) => {
+                warn!("{} line {}: {}", path, index + 1, e);
+                continue;
+            }
+        };
+        let totals = buckets
+            .entry((bucket.hour, bucket.r#in, bucket.out, bucket.source))
+            .or_default();
+        totals.up += bucket.up;
+        totals.down += bucket.down;
+        totals.flows += bucket.flows;
+    }
+
+    Ok(buckets)
+}
+
+// stn accounting -c [file] [--by hour|day] [--in [tag]] [--out [tag]] [--source [ip]]
+pub(crate) fn query(
+    args: &[String],
+    format: Option<config::Format>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config_path = None;
+    let mut by_day = false;
+    let mut filter: [Option<String>; 3] = [None, None, None];
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let value = iter
+            .next()
+            .ok_or_else(|| format!("{} needs a value", arg))?
+            .clone();
+        match arg.as_str() {
+            "-c" => config_path = Some(value),
+            "--by" => match value.as_str() {
+                "hour" => by_day = false,
+                "day" => by_day = true,
+                _ => Err(format!("--by {}: expect hour or day", value))?,
+            },
+            "--in" => filter[0] = Some(value),
+            "--out" => filter[1] = Some(value),
+            "--source" => filter[2] = Some(value),
+            _ => Err(format!("unknown option {}", arg))?,
+        }
+    }
+    let config_path = config_path.ok_or("-c not found")?;
+
+    let config = config::load(&config_path, format)?;
+    if config.setting.accounting.is_empty() {
+        Err("setting.accounting is not set")?
+    }
+    let buckets = read(&config.setting.accounting)?;
+
+    println!("time\tin\tout\tsource\tup\tdown\tflows");
+    for ((time, r#in, out, source), totals) in rollup(buckets, by_day) {
+        let fields = [&r#in, &out, &source];
+        if filter
+            .iter()
+            .zip(fields.iter())
+            .any(|(filter, field)| matches!(filter, Some(x) if x != *field))
+        {
+            continue;
+        }
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            format_time(time, by_day),
+            r#in,
+            out,
+            if source.is_empty() { "-" } else { &source },
+            totals.up,
+            totals.down,
+            totals.flows
+        );
+    }
+
+    Ok(())
+}
+
+// hourly buckets into daily ones, UTC
+fn rollup(buckets: BTreeMap<Key, Totals>, by_day: bool) -> BTreeMap<Key, Totals> {
+    if !by_day {
+        return buckets;
+    }
+
+    let mut days: BTreeMap<Key, Totals> = BTreeMap::new();
+    for ((hour, r#in, out, source), totals) in buckets {
+        let day = days
+            .entry((hour / 86400 * 86400, r#in, out, source))
+            .or_default();
+        day.up += totals.up;
+        day.down += totals.down;
+        day.flows += totals.flows;
+    }
+
+    days
+}
+
+// "2021-03-04 05:00" or "2021-03-04", UTC
+fn format_time(unix_secs: u64, by_day: bool) -> String {
+    let (year, month, day) = civil_from_days((unix_secs / 86400) as i64);
+
+    if by_day {
+        format!("{:04}-{:02}-{:02}", year, month, day)
+    } else {
+        format!(
+            "{:04}-{:02}-{:02} {:02}:00",
+            year,
+            month,
+            day,
+            unix_secs % 86400 / 3600
+        )
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+}
+
+#[test]
+fn test_rollup() {
+    let mut buckets = BTreeMap::new();
+    for (hour, up) in [(1614834000, 1), (1614837600, 2), (1614906000, 4)].iter() {
+        buckets.insert(
+            (
+                *hour,
+                "s5".to_string(),
+                "proxy".to_string(),
+                "10.0.0.2".to_string(),
+            ),
+            Totals {
+                up: *up,
+                down: 0,
+                flows: 1,
+            },
+        );
+    }
+
+    let days: Vec<(String, u64, u64)> = rollup(buckets, true)
+        .into_iter()
+        .map(|(key, totals)| (format_time(key.0, true), totals.up, totals.flows))
+        .collect();
+    assert_eq!(
+        days,
+        vec![
+            ("2021-03-04".to_string(), 3, 2),
+            ("2021-03-05".to_string(), 4, 1)
+        ]
+    );
+    assert_eq!(format_time(1614834000, false), "2021-03-04 05:00");
+}
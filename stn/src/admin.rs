@@ -0,0 +1,153 @@
+use crate::{
+    misc::{read_http_request, write_http_response},
+    route::{kill_flow, list_flows},
+};
+use log::*;
+use serde_json::json;
+use std::net::{IpAddr, SocketAddr};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+
+// json api on "127.0.0.1:9101" or a unix socket "/run/stn/admin.sock"
+//   GET /flows          live flows
+//   DELETE /flows/[id]  kill a flow
+// there is no authentication, a Host other than loopback is rejected against dns rebinding
+pub(crate) async fn start(address: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if let Ok(addr) = address.parse::<SocketAddr>() {
+        let listener = TcpListener::bind(addr).await?;
+        info!("admin listen on {}", addr);
+
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(handle(stream));
+                    }
+                    Err(e) => warn!("{}", e),
+                }
+            }
+        });
+
+        return Ok(());
+    }
+
+    cfg_if::cfg_if! {
+        if #[cfg(not(target_os = "windows"))] {
+            use std::os::unix::fs::FileTypeExt;
+
+            // left by the last run
+            if let Ok(metadata) = std::fs::metadata(address) {
+                if metadata.file_type().is_socket() {
+                    std::fs::remove_file(address)?;
+                }
+            }
+            let listener = tokio::net::UnixListener::bind(address)?;
+            // only the user of stn, whatever the umask is
+            {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(address, std::fs::Permissions::from_mode(0o600))?;
+            }
+            info!("admin listen on {}", address);
+
+            tokio::spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, _)) => {
+                            tokio::spawn(handle(stream));
+                        }
+                        Err(e) => warn!("{}", e),
+                    }
+                }
+            });
+
+            Ok(())
+        } else {
+            Err(format!("invalid admin address {}", address).into())
+        }
+    }
+}
+
+async fn handle<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S) {
+    let (method, path, host) = match read_http_request(&mut stream).await {
+        Ok(o) => o,
+        Err(e) => {
+            debug!("admin {}", e);
+            return;
+        }
+    };
+
+    let (status, body) = match host {
+        Some(x) if is_loopback_host(&x) => respond(&method, &path),
+        _ => (
+            "403 Forbidden",
+            json!({ "error": "host not allowed" }).to_string(),
+        ),
+    };
+    if let Err(e) =
+        write_http_response(&mut stream, status, "application/json", body.as_bytes()).await
+    {
+        debug!("admin {}", e);
+    }
+}
+
+fn respond(method: &str, path: &str) -> (&'static str, String) {
+    match (method, path.strip_prefix("/flows")) {
+        ("GET", Some("")) | ("GET", Some("/")) => (
+            "200 OK",
+            serde_json::to_string(&list_flows()).unwrap_or_default(),
+        ),
+        ("DELETE", Some(id)) => match id.strip_prefix('/').and_then(|x| x.parse::<u64>().ok()) {
+            Some(id) if kill_flow(id) => ("200 OK", json!({ "killed": id }).to_string()),
+            Some(_) => (
+                "404 Not Found",
+                json!({ "error": "flow not found" }).to_string(),
+            ),
+            None => (
+                "400 Bad Request",
+                json!({ "error": "invalid flow id" }).to_string(),
+            ),
+        },
+        _ => ("404 Not Found", json!({ "error": "not found" }).to_string()),
+    }
+}
+
+// "127.0.0.1:9101", "[::1]:9101" or "localhost", a browser sends the name a page was loaded from
+fn is_loopback_host(host: &str) -> bool {
+    if let Ok(addr) = host.parse::<SocketAddr>() {
+        return addr.ip().is_loopback();
+    }
+    if let Ok(ip) = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    {
+        return ip.is_loopback();
+    }
+    host.split(':')
+        .next()
+        .unwrap_or_default()
+        .eq_ignore_ascii_case("localhost")
+}
+
+#[test]
+fn test_respond() {
+    assert_eq!(respond("GET", "/flows"), ("200 OK", "[]".to_string()));
+    assert_eq!(respond("DELETE", "/flows/0").0, "404 Not Found");
+    assert_eq!(respond("DELETE", "/flows/x").0, "400 Bad Request");
+    assert_eq!(respond("POST", "/flows").0, "404 Not Found");
+}
+
+#[test]
+fn test_is_loopback_host() {
+    assert!(is_loopback_host("127.0.0.1:9101"));
+    assert!(is_loopback_host("[::1]:9101"));
+    assert!(is_loopback_host("::1"));
+    assert!(is_loopback_host("localhost"));
+    assert!(is_loopback_host("LocalHost:9101"));
+    assert!(!is_loopback_host("evil.com:9101"));
+    assert!(!is_loopback_host("127.0.0.1.evil.com"));
+    assert!(!is_loopback_host("10.0.0.1:9101"));
+    assert!(!is_loopback_host("stn"));
+}
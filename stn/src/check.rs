@@ -0,0 +1,196 @@
+use crate::{
+    config::{self, Config},
+    route::{ResolveDaddr, RouteConfig},
+};
+
+// lint a configuration without binding anything, return false if any error found
+pub(crate) fn check(path: &str, format: Option<config::Format>) -> bool {
+    let (errors, warnings) = match config::load(path, format) {
+        Ok(config) => {
+            let (errors, warnings) = lint(&config);
+            let locate = |x: Vec<String>| -> Vec<String> {
+                x.iter().map(|x| config.origins.locate(x)).collect()
+            };
+            (locate(errors), locate(warnings))
+        }
+        Err(e) => (e.0, Vec::new()),
+    };
+
+    for error in &errors {
+        println!("error: {}", error);
+    }
+    for warning in &warnings {
+        println!("warning: {}", warning);
+    }
+    println!("{} error(s), {} warning(s)", errors.len(), warnings.len());
+
+    errors.is_empty()
+}
+
+pub(crate) fn lint(config: &Config) -> (Vec<String>, Vec<String>) {
+    let mut errors = Vec::new();
+    let mut warnings = Vec::new();
+
+    if let Err(e) = crate::route::open_geoip(&config.setting.geoip) {
+        errors.push(e);
+    }
+    let geosite = match crate::route::open_geosite(&config.setting.geosite) {
+        Ok(o) => o,
+        Err(e) => {
+            errors.push(e);
+            None
+        }
+    };
+
+    // file lists, regexes, geoip and geosite, sub-rules included
+    for (index, route) in config.route.iter().enumerate() {
+        crate::route::parse_rule(
+            &route.rule(),
+            &format!("route[{}]", index),
+            geosite.as_ref(),
+            !config.setting.geoip.is_empty(),
+            &mut errors,
+        );
+    }
+
+    // shadowed routes
+    for (index, route) in config.route.iter().enumerate() {
+        if let Some(shadow_index) = config.route[..index]
+            .iter()
+            .position(|x| is_shadowed_by(route, x, config.setting.resolve_daddr))
+        {
+            warnings.push(format!(
+                "route[{}]: unreachable, shadowed by route[{}]",
+                index, shadow_index
+            ));
+        }
+    }
+
+    // unreachable outs, out[0] is the default out
+    for (index, out) in config.out.iter().enumerate().skip(1) {
+        if !config
+            .route
+            .iter()
+            .any(|x| x.jump == out.tag() || matches!(&x.quota, Some(q) if q.fallback == out.tag()))
+        {
+            warnings.push(format!(
+                "out[{}]: unreachable, no route jumps to {}",
+                index,
+                out.tag()
+            ));
+        }
+    }
+
+    // implicit default out
+    if !config.route.iter().any(is_catch_all) {
+        if let Some(out) = config.out.first() {
+            warnings.push(format!(
+                "route: no catch-all route, unmatched flows go to out[0] {}",
+                out.tag()
+            ));
+        }
+    }
+
+    (errors, warnings)
+}
+
+// every flow matched by route is also matched by earlier.
+// a route with resolve_daddr is matched again by the ip, where only such routes are looked at
+fn is_shadowed_by(route: &RouteConfig, earlier: &RouteConfig, default: ResolveDaddr) -> bool {
+    let resolves =
+        |x: &RouteConfig| x.resolve_daddr.unwrap_or(default) == ResolveDaddr::IfNoDomainMatch;
+    (resolves(earlier) || !resolves(route))
+        && covers(&earlier.tag, &route.tag)
+        && covers(&earlier.network, &route.network)
+        && covers_addr(&earlier.saddr, &route.saddr)
+        && covers(&earlier.sport, &route.sport)
+        && covers_addr(&earlier.daddr, &route.daddr)
+        && covers(&earlier.dport, &route.dport)
+        && covers_addr(&earlier.dns_domain, &route.dns_domain)
+        && covers(&earlier.process_name, &route.process_name)
+        && covers(&earlier.process_path, &route.process_path)
+        && covers(&earlier.uid, &route.uid)
+        && covers(&earlier.gid, &route.gid)
+        && covers_addr(&earlier.sniff_domain, &route.sniff_domain)
+        && covers(&earlier.alpn, &route.alpn)
+        // sub-rules literally, more "all" rules only narrow a route
+        && (earlier.not.is_none() || earlier.not == route.not)
+        && covers(&earlier.any, &route.any)
+        && earlier.all.iter().all(|x| route.all.contains(x))
+}
+
+fn is_catch_all(route: &RouteConfig) -> bool {
+    route.tag.is_empty()
+        && route.network.is_empty()
+        && route.saddr.is_empty()
+        && route.sport.is_empty()
+        && route.daddr.is_empty()
+        && route.dport.is_empty()
+        && route.dns_domain.is_empty()
+        && route.process_name.is_empty()
+        && route.process_path.is_empty()
+        && route.uid.is_empty()
+        && route.gid.is_empty()
+        && route.sniff_domain.is_empty()
+        && route.alpn.is_empty()
+        && route.not.is_none()
+        && route.any.is_empty()
+        && route.all.is_empty()
+}
+
+// empty matches everything, otherwise compare entries literally
+fn covers<T: PartialEq>(earlier: &[T], later: &[T]) -> bool {
+    earlier.is_empty() || (!later.is_empty() && later.iter().all(|x| earlier.contains(x)))
+}
+
+// "!" entries exclude, so later has to exclude at least as much
+fn covers_addr(earlier: &[String], later: &[String]) -> bool {
+    let (earlier_negated, earlier): (Vec<&String>, Vec<&String>) =
+        earlier.iter().partition(|x| x.starts_with('!'));
+    let (later_negated, later): (Vec<&String>, Vec<&String>) =
+        later.iter().partition(|x| x.starts_with('!'));
+
+    covers(&earlier, &later) && earlier_negated.iter().all(|x| later_negated.contains(x))
+}
+
+#[test]
+fn test_lint() {
+    let root = serde_json::json!({
+        "out": [
+            { "tag": "origin", "protocol": "origin" },
+            { "tag": "socks5", "protocol": "socks5", "address": "1.2.3.4:1080" },
+            { "tag": "drop", "protocol": "drop" }
+        ],
+        "route": [
+            { "daddr": ["domain a.com", "domain b.com"], "jump": "socks5" },
+            { "daddr": ["domain a.com"], "dport": [443], "jump": "origin" },
+            { "daddr": ["regex ("], "jump": "origin" },
+            { "daddr": ["domain c.com", "!full b.c.com"], "jump": "origin" },
+            { "daddr": ["domain c.com"], "jump": "origin" },
+            { "daddr": ["domain c.com", "!full b.c.com", "!full d.c.com"], "jump": "origin" },
+            { "any": [{ "network": ["udp"] }, { "dport": [443] }], "jump": "origin" },
+            { "any": [{ "daddr": ["geoip CN"] }], "jump": "origin" },
+            { "daddr": ["domain a.com", "domain b.com"], "jump": "origin", "resolve_daddr": "if_no_domain_match" },
+            { "daddr": ["domain b.com"], "jump": "origin", "resolve_daddr": "if_no_domain_match" }
+        ]
+    });
+    let config = config::from_value(&root).unwrap();
+
+    let (errors, warnings) = lint(&config);
+    assert_eq!(errors.len(), 2);
+    assert!(errors[0].starts_with("route[2].daddr[0]: "));
+    assert_eq!(
+        errors[1],
+        "route[7].any[0].daddr: geoip needs setting.geoip"
+    );
+    assert_eq!(
+        warnings,
+        vec![
+            "route[1]: unreachable, shadowed by route[0]",
+            "route[5]: unreachable, shadowed by route[3]",
+            "route[9]: unreachable, shadowed by route[8]",
+            "out[2]: unreachable, no route jumps to drop",
+            "route: no catch-all route, unmatched flows go to out[0] origin",
+        ]
+    );
+}
@@ -0,0 +1,196 @@
+use crate::*;
+use serde::{Deserialize, Deserializer};
+use std::{net::SocketAddr, time::Duration};
+
+pub(crate) struct Config {
+    pub(crate) setting: SettingConfig,
+    pub(crate) resolve: resolve::ResolveConfig,
+    pub(crate) r#in: Vec<InConfig>,
+    pub(crate) out: Vec<OutConfig>,
+    pub(crate) route: Vec<route::RouteConfig>,
+    pub(crate) origins: super::Origins,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SettingConfig {
+    #[serde(default)]
+    pub(crate) daemon: bool,
+    #[serde(default)]
+    pub(crate) pid_file: String,
+    #[serde(default)]
+    pub(crate) log_level: LogLevel,
+    #[serde(default)]
+    pub(crate) log_format: LogFormat,
+    #[serde(default)]
+    pub(crate) log_file: String,
+    #[serde(default = "default_log_file_max")]
+    pub(crate) log_file_max: u64,
+    #[serde(default)]
+    pub(crate) access_log: String,
+    #[serde(default = "default_log_file_max")]
+    pub(crate) access_log_max: u64,
+    #[serde(default)]
+    pub(crate) route_log: bool,
+    #[serde(default)]
+    pub(crate) accounting: String,
+    #[serde(default)]
+    pub(crate) quota_state: String,
+    #[serde(default)]
+    pub(crate) geoip: String,
+    #[serde(default)]
+    pub(crate) geosite: String,
+    #[serde(default)]
+    pub(crate) resolve_daddr: route::ResolveDaddr,
+    pub(crate) uid: Option<u32>,
+    pub(crate) gid: Option<u32>,
+    #[serde(default)]
+    pub(crate) capabilities: Vec<String>,
+    #[serde(
+        default = "default_drain_timeout",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub(crate) drain_timeout: Duration,
+    pub(crate) metrics: Option<SocketAddr>,
+    #[serde(default)]
+    pub(crate) admin: String,
+    pub(crate) hook: Option<hook::HookConfig>,
+    pub(crate) sniff: Option<route::SniffConfig>,
+}
+
+impl Default for SettingConfig {
+    fn default() -> Self {
+        Self {
+            daemon: false,
+            pid_file: String::new(),
+            log_level: LogLevel::default(),
+            log_format: LogFormat::default(),
+            log_file: String::new(),
+            log_file_max: default_log_file_max(),
+            access_log: String::new(),
+            access_log_max: default_log_file_max(),
+            route_log: false,
+            accounting: String::new(),
+            quota_state: String::new(),
+            geoip: String::new(),
+            geosite: String::new(),
+            resolve_daddr: route::ResolveDaddr::default(),
+            uid: None,
+            gid: None,
+            capabilities: Vec::new(),
+            drain_timeout: default_drain_timeout(),
+            metrics: None,
+            admin: String::new(),
+            hook: None,
+            sniff: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    #[default]
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Clone)]
+pub(crate) enum InConfig {
+    Http(http::InConfig),
+    Origin(origin::InConfig),
+    Socks5(socks5::InConfig),
+    #[cfg(feature = "private")]
+    Stn(serde_json::Value),
+    #[cfg(not(target_os = "windows"))]
+    Tproxy(tproxy::InConfig),
+}
+
+impl InConfig {
+    pub(crate) fn tag(&self) -> &str {
+        match self {
+            InConfig::Http(config) => &config.tag,
+            InConfig::Origin(config) => &config.tag,
+            InConfig::Socks5(config) => &config.tag,
+            #[cfg(feature = "private")]
+            InConfig::Stn(config) => config["tag"].as_str().unwrap_or_default(),
+            #[cfg(not(target_os = "windows"))]
+            InConfig::Tproxy(config) => &config.tag,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) enum OutConfig {
+    Dns(dns::OutConfig),
+    Drop(drop::OutConfig),
+    Http(http::OutConfig),
+    Origin(origin::OutConfig),
+    Socks5(socks5::OutConfig),
+    #[cfg(feature = "private")]
+    Stn(serde_json::Value),
+}
+
+impl OutConfig {
+    pub(crate) fn tag(&self) -> &str {
+        match self {
+            OutConfig::Dns(config) => &config.tag,
+            OutConfig::Drop(config) => &config.tag,
+            OutConfig::Http(config) => &config.tag,
+            OutConfig::Origin(config) => &config.tag,
+            OutConfig::Socks5(config) => &config.tag,
+            #[cfg(feature = "private")]
+            OutConfig::Stn(config) => config["tag"].as_str().unwrap_or_default(),
+        }
+    }
+}
+
+// seconds in configuration, 0.5 means 500ms
+pub(crate) fn deserialize_secs<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let secs = f64::deserialize(deserializer)?;
+    if !secs.is_finite() || secs < 0f64 {
+        Err(serde::de::Error::custom(format!(
+            "invalid duration {}, expect non-negative seconds",
+            secs
+        )))?
+    }
+
+    Ok(Duration::from_nanos((secs * 1_000_000_000f64) as u64))
+}
+
+pub(crate) fn default_true() -> bool {
+    true
+}
+
+pub(crate) fn default_log_file_max() -> u64 {
+    1024
+}
+
+pub(crate) fn default_drain_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+pub(crate) fn default_tcp_keepalive_interval() -> Duration {
+    Duration::from_secs(30)
+}
+
+pub(crate) fn default_tcp_timeout() -> Duration {
+    Duration::from_secs(300)
+}
+
+pub(crate) fn default_udp_timeout() -> Duration {
+    Duration::from_secs(60)
+}
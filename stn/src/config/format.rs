@@ -0,0 +1,77 @@
+use serde_json::Value;
+use std::{fs::File, io::Read, path::Path, str::FromStr};
+
+// every format is converted to the same json value before parsing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    // json with comments if the extension is unknown
+    pub(crate) fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("yaml") | Some("yml") => Format::Yaml,
+            _ => Format::Json,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "toml" => Ok(Format::Toml),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            _ => Err(format!("unknown format {}, expect json, toml or yaml", s)),
+        }
+    }
+}
+
+pub(crate) fn read_value(path: &Path, format: Format) -> Result<Value, Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+
+    match format {
+        Format::Json => {
+            let stripped = json_comments::StripComments::new(file);
+            Ok(serde_json::from_reader(stripped)?)
+        }
+        Format::Toml => {
+            let mut buf = String::new();
+            file.read_to_string(&mut buf)?;
+            Ok(toml::from_str(&buf)?)
+        }
+        Format::Yaml => Ok(serde_yaml::from_reader(file)?),
+    }
+}
+
+#[test]
+fn test_format() {
+    let dir = crate::misc::TestDir::new("format");
+    let json = dir.write(
+        "a.json",
+        "{ \"setting\": { \"drain_timeout\": 0.5 },\n// comment\n\"route\": [{ \"dport\": [53] }] }",
+    );
+    let toml = dir.write(
+        "a.toml",
+        "[setting]\ndrain_timeout = 0.5\n# comment\n[[route]]\ndport = [53]\n",
+    );
+    let yaml = dir.write(
+        "a.yml",
+        "setting: { drain_timeout: 0.5 }\n# comment\nroute:\n  - dport: [53]\n",
+    );
+
+    let value = read_value(Path::new(&json), Format::Json).unwrap();
+    for path in [&toml, &yaml].iter() {
+        let path = Path::new(path);
+        assert_eq!(read_value(path, Format::from_path(path)).unwrap(), value);
+    }
+    assert!(read_value(Path::new(&toml), Format::Json).is_err());
+    assert_eq!("yaml".parse::<Format>(), Ok(Format::Yaml));
+    assert!("ini".parse::<Format>().is_err());
+}
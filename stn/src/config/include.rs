@@ -0,0 +1,247 @@
+use super::{read_value, Format};
+use serde_json::{Map, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+// arrays which can be split across files
+const SECTIONS: [&str; 3] = ["in", "out", "route"];
+
+// (file, index in that file) of every merged array element
+#[derive(Default)]
+pub(crate) struct Origins {
+    main_path: String,
+    sections: HashMap<&'static str, Vec<(String, usize)>>,
+}
+
+impl Origins {
+    // "route[5].jump: ..." -> "routes.d/b.json: route[1].jump: ..."
+    pub(crate) fn locate(&self, error: &str) -> String {
+        for section in SECTIONS.iter() {
+            let rest = match error
+                .strip_prefix(section)
+                .and_then(|x| x.strip_prefix('['))
+            {
+                Some(s) => s,
+                None => continue,
+            };
+            let end = match rest.find(']') {
+                Some(s) => s,
+                None => continue,
+            };
+            let origin = rest[..end]
+                .parse::<usize>()
+                .ok()
+                .and_then(|x| self.sections.get(section)?.get(x));
+            if let Some((file, index)) = origin {
+                if *file != self.main_path {
+                    return format!("{}: {}[{}]{}", file, section, index, &rest[end + 1..]);
+                }
+            }
+        }
+
+        error.to_string()
+    }
+}
+
+// read path and every file it includes into one root.
+// own arrays first, then included files in listed order, matches sorted by name.
+// format only applies to path, included files are detected by extension
+pub(crate) fn load_with_include(
+    path: &str,
+    format: Option<Format>,
+) -> Result<(Value, Origins), Vec<String>> {
+    let mut merged = Map::new();
+    let mut origins = Origins::default();
+    let mut visited = HashSet::new();
+    let mut errors = Vec::new();
+
+    let main_format = format.unwrap_or_else(|| Format::from_path(Path::new(path)));
+    merge_file(
+        Path::new(path),
+        main_format,
+        true,
+        &mut merged,
+        &mut origins,
+        &mut visited,
+        &mut errors,
+    );
+
+    origins.main_path = path.to_string();
+    if errors.is_empty() {
+        Ok((Value::Object(merged), origins))
+    } else {
+        Err(errors)
+    }
+}
+
+fn merge_file(
+    path: &Path,
+    format: Format,
+    is_main: bool,
+    merged: &mut Map<String, Value>,
+    origins: &mut Origins,
+    visited: &mut HashSet<PathBuf>,
+    errors: &mut Vec<String>,
+) {
+    let display = path.display().to_string();
+
+    // include loop or the same file twice
+    match path.canonicalize() {
+        Ok(o) => {
+            if !visited.insert(o) {
+                errors.push(format!("{}: included more than once", display));
+                return;
+            }
+        }
+        Err(e) => {
+            errors.push(format!("{}: {}", display, e));
+            return;
+        }
+    }
+
+    let root = match read_value(path, format) {
+        Ok(Value::Object(o)) => o,
+        Ok(_) => {
+            errors.push(format!("{}: configuration is not an object", display));
+            return;
+        }
+        Err(e) => {
+            errors.push(format!("{}: {}", display, e));
+            return;
+        }
+    };
+
+    let mut includes = Vec::new();
+    for (key, value) in root {
+        if key == "include" {
+            match value {
+                Value::String(s) => includes.push(s),
+                Value::Array(array) => {
+                    for (index, value) in array.into_iter().enumerate() {
+                        match value {
+                            Value::String(s) => includes.push(s),
+                            _ => errors
+                                .push(format!("{}: include[{}]: expect a string", display, index)),
+                        }
+                    }
+                }
+                _ => errors.push(format!("{}: include: expect an array", display)),
+            }
+        } else if let Some(section) = SECTIONS.iter().find(|x| **x == key) {
+            let array = match value {
+                Value::Array(array) => array,
+                _ => {
+                    errors.push(format!("{}: {}: expect an array", display, key));
+                    continue;
+                }
+            };
+            let section_origins = origins.sections.entry(section).or_default();
+            let merged_array = merged
+                .entry(key)
+                .or_insert_with(|| Value::Array(Vec::new()));
+            for (index, value) in array.into_iter().enumerate() {
+                section_origins.push((display.clone(), index));
+                if let Value::Array(merged_array) = merged_array {
+                    merged_array.push(value);
+                }
+            }
+        } else if is_main {
+            merged.insert(key, value);
+        } else {
+            errors.push(format!(
+                "{}: {}: only in, out, route and include are allowed in an included file",
+                display, key
+            ));
+        }
+    }
+
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    for include in includes {
+        match expand_include(base, &include) {
+            Ok(paths) => {
+                for path in paths {
+                    let format = Format::from_path(&path);
+                    merge_file(&path, format, false, merged, origins, visited, errors);
+                }
+            }
+            Err(e) => errors.push(format!("{}: include {}: {}", display, include, e)),
+        }
+    }
+}
+
+// "outs/*.json" or "routes.d/", relative to the including file
+fn expand_include(base: &Path, include: &str) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let path = base.join(include);
+
+    let mut paths = if path.is_dir() {
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(&path)? {
+            let entry_path = entry?.path();
+            if entry_path.is_file() && is_config_file(&entry_path) {
+                paths.push(entry_path);
+            }
+        }
+        paths
+    } else if include.contains(&['*', '?', '['][..]) {
+        glob::glob(&path.to_string_lossy())?
+            .collect::<Result<Vec<PathBuf>, _>>()?
+            .into_iter()
+            .filter(|x| x.is_file())
+            .collect()
+    } else {
+        vec![path]
+    };
+    paths.sort();
+
+    Ok(paths)
+}
+
+fn is_config_file(path: &Path) -> bool {
+    match path.extension().and_then(|x| x.to_str()) {
+        Some(s) => ["json", "toml", "yaml", "yml"].contains(&s),
+        None => false,
+    }
+}
+
+#[test]
+fn test_include() {
+    let dir = crate::misc::TestDir::new("include");
+    let main_path = dir.write(
+        "main.json",
+        "{\n// main file\n\"include\": [\"outs.json\", \"routes.d/\"], \"route\": [{ \"jump\": \"a\" }] }",
+    );
+    dir.write("outs.json", r#"{ "out": [{ "tag": "a" }] }"#);
+    dir.write("routes.d/2.json", r#"{ "route": [{ "jump": "c" }] }"#);
+    dir.write(
+        "routes.d/1.json",
+        r#"{ "route": [{ "jump": "b" }], "setting": {} }"#,
+    );
+    dir.write("routes.d/ignored.txt", "");
+
+    let errors = load_with_include(&main_path, None).err().unwrap();
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].ends_with(
+        "1.json: setting: only in, out, route and include are allowed in an included file"
+    ));
+
+    dir.write("routes.d/1.json", r#"{ "route": [{ "jump": "b" }] }"#);
+    let (root, origins) = load_with_include(&main_path, None).unwrap();
+    let jumps: Vec<&str> = root["route"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|x| x["jump"].as_str().unwrap())
+        .collect();
+    assert_eq!(jumps, vec!["a", "b", "c"]);
+    assert_eq!(root["out"][0]["tag"], "a");
+    assert!(root.get("include").is_none());
+    assert!(origins
+        .locate("route[2].jump: out c not found")
+        .ends_with("2.json: route[0].jump: out c not found"));
+    assert_eq!(
+        origins.locate("route[0].jump: out a not found"),
+        "route[0].jump: out a not found"
+    );
+}
@@ -0,0 +1,99 @@
+use serde_json::Value;
+
+// replace ${NAME} and ${file:/path} in every string value, "$${" keeps a literal "${".
+// path is the location of value, used in errors
+pub(crate) fn interpolate(value: &mut Value, path: &str, errors: &mut Vec<String>) {
+    match value {
+        Value::String(s) => match interpolate_str(s) {
+            Ok(o) => *s = o,
+            Err(e) => errors.push(format!("{}: {}", path, e)),
+        },
+        Value::Array(array) => {
+            for (index, value) in array.iter_mut().enumerate() {
+                interpolate(value, &format!("{}[{}]", path, index), errors);
+            }
+        }
+        Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                interpolate(value, &path, errors);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn interpolate_str(s: &str) -> Result<String, String> {
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(start) = rest.find("${") {
+        // "$${" is an escaped "${"
+        if rest[..start].ends_with('$') {
+            result.push_str(&rest[..start - 1]);
+            result.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+
+        result.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unterminated ${{ in {}", s))?;
+        let name = &rest[start + 2..start + end];
+        result.push_str(&lookup(name)?);
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+
+    Ok(result)
+}
+
+fn lookup(name: &str) -> Result<String, String> {
+    if let Some(file) = name.strip_prefix("file:") {
+        // secret files usually end with a newline
+        let content =
+            std::fs::read_to_string(file).map_err(|e| format!("secret file {}: {}", file, e))?;
+        return Ok(content.trim_end_matches(&['\r', '\n'][..]).to_string());
+    }
+
+    if name.is_empty() {
+        Err("empty variable name in ${}")?
+    }
+    std::env::var(name).map_err(|e| match e {
+        std::env::VarError::NotPresent => format!("environment variable {} not set", name),
+        std::env::VarError::NotUnicode(_) => format!("environment variable {} not unicode", name),
+    })
+}
+
+#[test]
+fn test_interpolate() {
+    std::env::set_var("STN_TEST_INTERPOLATE_HOST", "1.2.3.4");
+    let dir = crate::misc::TestDir::new("interpolate");
+    let secret = dir.write("secret", "5678\n");
+
+    let mut root = serde_json::json!({
+        "out": [{
+            "address": format!("${{STN_TEST_INTERPOLATE_HOST}}:${{file:{}}}", secret),
+            "regex": "a$${b}$",
+            "port": 80
+        }],
+        "route": [{ "daddr": ["${STN_TEST_INTERPOLATE_MISSING}", "${unterminated"] }]
+    });
+    let mut errors = Vec::new();
+    interpolate(&mut root, "", &mut errors);
+
+    assert_eq!(root["out"][0]["address"], "1.2.3.4:5678");
+    assert_eq!(root["out"][0]["regex"], "a${b}$");
+    assert_eq!(root["out"][0]["port"], 80);
+    assert_eq!(errors.len(), 2);
+    assert_eq!(
+        errors[0],
+        "route[0].daddr[0]: environment variable STN_TEST_INTERPOLATE_MISSING not set"
+    );
+    assert!(errors[1].starts_with("route[0].daddr[1]: unterminated ${"));
+}
@@ -0,0 +1,343 @@
+use super::*;
+use crate::*;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::{collections::HashSet, fmt, net::SocketAddr};
+
+// every problem found while loading, one "path: reason" per line
+#[derive(Debug)]
+pub(crate) struct ConfigError(pub(crate) Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.join("\n"))
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// format is detected by extension if None
+pub(crate) fn load(path: &str, format: Option<Format>) -> Result<Config, ConfigError> {
+    let (mut root, origins) = load_with_include(path, format).map_err(ConfigError)?;
+
+    // point errors of included entries at their own file
+    let locate = |e: ConfigError| ConfigError(e.0.iter().map(|x| origins.locate(x)).collect());
+
+    let mut errors = Vec::new();
+    interpolate(&mut root, "", &mut errors);
+    if !errors.is_empty() {
+        return Err(locate(ConfigError(errors)));
+    }
+
+    let mut config = from_value(&root).map_err(locate)?;
+    config.origins = origins;
+
+    Ok(config)
+}
+
+pub(crate) fn from_value(root: &Value) -> Result<Config, ConfigError> {
+    let mut errors = Vec::new();
+
+    let root = root
+        .as_object()
+        .ok_or_else(|| ConfigError(vec!["configuration is not an object".to_string()]))?;
+    for key in root.keys() {
+        if !["setting", "resolve", "in", "out", "route"].contains(&key.as_str()) {
+            errors.push(format!("{}: unknown field", key));
+        }
+    }
+
+    let setting = match root.get("setting") {
+        Some(value) => parse(value, "setting", &mut errors).unwrap_or_default(),
+        None => SettingConfig::default(),
+    };
+    let resolve = match root.get("resolve") {
+        Some(value) => parse(value, "resolve", &mut errors).unwrap_or_default(),
+        None => resolve::ResolveConfig::default(),
+    };
+
+    let mut r#in = Vec::new();
+    for (index, value) in array(root.get("in"), "in", &mut errors).iter().enumerate() {
+        if let Some(config) = parse_in(value, &format!("in[{}]", index), &mut errors) {
+            r#in.push(config);
+        }
+    }
+
+    let mut out = Vec::new();
+    for (index, value) in array(root.get("out"), "out", &mut errors)
+        .iter()
+        .enumerate()
+    {
+        if let Some(config) = parse_out(value, &format!("out[{}]", index), &mut errors) {
+            out.push(config);
+        }
+    }
+
+    let mut route = Vec::new();
+    for (index, value) in array(root.get("route"), "route", &mut errors)
+        .iter()
+        .enumerate()
+    {
+        if let Some(config) = parse(value, &format!("route[{}]", index), &mut errors) {
+            route.push(config);
+        }
+    }
+
+    let config = Config {
+        setting,
+        resolve,
+        r#in,
+        out,
+        route,
+        origins: Origins::default(),
+    };
+    validate(&config, &mut errors);
+
+    if errors.is_empty() {
+        Ok(config)
+    } else {
+        Err(ConfigError(errors))
+    }
+}
+
+// cross reference checks, schema errors are reported by parse()
+fn validate(config: &Config, errors: &mut Vec<String>) {
+    for (index, name) in config.setting.capabilities.iter().enumerate() {
+        if privilege::parse_capability(name).is_none() {
+            errors.push(format!(
+                "setting.capabilities[{}]: unknown capability {}",
+                index, name
+            ));
+        }
+    }
+
+    // the admin api has no authentication, so it is never reachable from the network
+    let admin = &config.setting.admin;
+    match admin.parse::<SocketAddr>() {
+        Ok(addr) if !addr.ip().is_loopback() => errors.push(format!(
+            "setting.admin: {} is not a loopback address",
+            admin
+        )),
+        Err(_) if !admin.is_empty() && !admin.starts_with('/') => errors.push(format!(
+            "setting.admin: {} is neither a socket address nor an absolute unix socket path",
+            admin
+        )),
+        _ => {}
+    }
+
+    if let Some(hook) = &config.setting.hook {
+        if hook.command.is_empty() && hook.url.is_empty() {
+            errors.push("setting.hook: command or url is required".to_string());
+        }
+        if !hook.url.is_empty() {
+            if let Err(e) = hook::parse_url(&hook.url) {
+                errors.push(format!("setting.hook.url: {}", e));
+            }
+        }
+        for (index, event) in hook.events.iter().enumerate() {
+            if !hook::EVENTS.contains(&event.as_str()) {
+                errors.push(format!(
+                    "setting.hook.events[{}]: unknown event {}, expect one of {}",
+                    index,
+                    event,
+                    hook::EVENTS.join(", ")
+                ));
+            }
+        }
+        if hook.connect_failures == 0 {
+            errors.push("setting.hook.connect_failures: expect at least 1".to_string());
+        }
+    }
+
+    if matches!(&config.setting.sniff, Some(x) if x.bytes == 0) {
+        errors.push("setting.sniff.bytes: expect at least 1".to_string());
+    }
+
+    if config.out.is_empty() {
+        errors.push("out: at least one out is required".to_string());
+    }
+
+    let mut in_tags = HashSet::new();
+    for (index, r#in) in config.r#in.iter().enumerate() {
+        if !in_tags.insert(r#in.tag()) {
+            errors.push(format!("in[{}].tag: duplicate tag {}", index, r#in.tag()));
+        }
+    }
+
+    let mut out_tags = HashSet::new();
+    for (index, out) in config.out.iter().enumerate() {
+        if !out_tags.insert(out.tag()) {
+            errors.push(format!("out[{}].tag: duplicate tag {}", index, out.tag()));
+        }
+    }
+
+    for (index, server) in config.resolve.server.iter().enumerate() {
+        if let Err(e) = dns::check_server(server) {
+            errors.push(format!("resolve.server[{}]: {}", index, e));
+        }
+    }
+    for (index, out) in config.out.iter().enumerate() {
+        if let OutConfig::Dns(dns) = out {
+            for (server_index, server) in dns.server.iter().enumerate() {
+                if let Err(e) = dns::check_server(server) {
+                    errors.push(format!("out[{}].server[{}]: {}", index, server_index, e));
+                }
+            }
+        }
+    }
+
+    for (index, route) in config.route.iter().enumerate() {
+        if !out_tags.contains(route.jump.as_str()) {
+            errors.push(format!(
+                "route[{}].jump: out {} not found",
+                index, route.jump
+            ));
+        }
+        if let Some(quota) = &route.quota {
+            if !quota.fallback.is_empty() && !out_tags.contains(quota.fallback.as_str()) {
+                errors.push(format!(
+                    "route[{}].quota.fallback: out {} not found",
+                    index, quota.fallback
+                ));
+            }
+        }
+    }
+}
+
+fn array<'a>(value: Option<&'a Value>, path: &str, errors: &mut Vec<String>) -> &'a [Value] {
+    match value {
+        None => &[],
+        Some(Value::Array(array)) => array,
+        Some(_) => {
+            errors.push(format!("{}: expect an array", path));
+            &[]
+        }
+    }
+}
+
+fn parse_in(value: &Value, path: &str, errors: &mut Vec<String>) -> Option<InConfig> {
+    let (protocol, value) = split_protocol(value, path, errors)?;
+
+    match protocol.as_str() {
+        "http" => parse(&value, path, errors).map(InConfig::Http),
+        "origin" => parse(&value, path, errors).map(InConfig::Origin),
+        "socks5" => parse(&value, path, errors).map(InConfig::Socks5),
+        #[cfg(feature = "private")]
+        "stn" => Some(InConfig::Stn(value)),
+        #[cfg(not(target_os = "windows"))]
+        "tproxy" => parse(&value, path, errors).map(InConfig::Tproxy),
+        protocol => {
+            errors.push(format!("{}.protocol: {} not support", path, protocol));
+            None
+        }
+    }
+}
+
+fn parse_out(value: &Value, path: &str, errors: &mut Vec<String>) -> Option<OutConfig> {
+    let (protocol, value) = split_protocol(value, path, errors)?;
+
+    match protocol.as_str() {
+        "dns" => parse(&value, path, errors).map(OutConfig::Dns),
+        "drop" => parse(&value, path, errors).map(OutConfig::Drop),
+        "http" => parse(&value, path, errors).map(OutConfig::Http),
+        "origin" => parse(&value, path, errors).map(OutConfig::Origin),
+        "socks5" => parse(&value, path, errors).map(OutConfig::Socks5),
+        #[cfg(feature = "private")]
+        "stn" => Some(OutConfig::Stn(value)),
+        protocol => {
+            errors.push(format!("{}.protocol: {} not support", path, protocol));
+            None
+        }
+    }
+}
+
+// protocol selects the schema, the rest is checked with deny_unknown_fields
+fn split_protocol(value: &Value, path: &str, errors: &mut Vec<String>) -> Option<(String, Value)> {
+    let mut value = value.clone();
+    let object = match value.as_object_mut() {
+        Some(s) => s,
+        None => {
+            errors.push(format!("{}: expect an object", path));
+            return None;
+        }
+    };
+
+    match object.remove("protocol") {
+        Some(Value::String(protocol)) => Some((protocol, value)),
+        Some(_) => {
+            errors.push(format!("{}.protocol: expect a string", path));
+            None
+        }
+        None => {
+            errors.push(format!("{}.protocol: missing field", path));
+            None
+        }
+    }
+}
+
+fn parse<T: DeserializeOwned>(value: &Value, path: &str, errors: &mut Vec<String>) -> Option<T> {
+    match serde_path_to_error::deserialize(value) {
+        Ok(o) => Some(o),
+        Err(e) => {
+            let inner_path = e.path().to_string();
+            if inner_path == "." {
+                errors.push(format!("{}: {}", path, e.inner()));
+            } else {
+                errors.push(format!("{}.{}: {}", path, inner_path, e.inner()));
+            }
+            None
+        }
+    }
+}
+
+#[test]
+fn test_config_error_path() {
+    let root = serde_json::json!({
+        "setting": { "log_level": "verbose" },
+        "in": [
+            { "tag": "a", "protocol": "socks5", "address": "[::]:1080" },
+            { "tag": "b", "protocol": "http", "address": "[::]:1081", "tcp_keepalive_inverval": 1 },
+            { "tag": "c", "protocol": "socks5", "address": "localhost" }
+        ],
+        "out": [
+            { "tag": "origin", "protocol": "origin" }
+        ],
+        "route": [
+            { "jump": "socks5" }
+        ]
+    });
+
+    let errors = match from_value(&root) {
+        Ok(_) => panic!("invalid configuration accepted"),
+        Err(e) => e.0,
+    };
+    assert!(errors.iter().any(|x| x.starts_with("setting.log_level: ")));
+    assert!(errors
+        .iter()
+        .any(|x| x.starts_with("in[1].tcp_keepalive_inverval: unknown field")));
+    assert!(errors.iter().any(|x| x.starts_with("in[2].address: ")));
+    assert!(errors
+        .iter()
+        .any(|x| x == "route[0].jump: out socks5 not found"));
+    assert_eq!(errors.len(), 4);
+}
+
+#[test]
+fn test_admin_address() {
+    let errors = |admin: &str| -> Vec<String> {
+        let root = serde_json::json!({
+            "setting": { "admin": admin },
+            "out": [{ "tag": "origin", "protocol": "origin" }]
+        });
+        from_value(&root).err().map(|e| e.0).unwrap_or_default()
+    };
+
+    assert!(errors("127.0.0.1:9101").is_empty());
+    assert!(errors("[::1]:9101").is_empty());
+    assert!(errors("/run/stn/admin.sock").is_empty());
+    assert_eq!(
+        errors("0.0.0.0:9101"),
+        vec!["setting.admin: 0.0.0.0:9101 is not a loopback address"]
+    );
+    assert_eq!(errors("admin.sock").len(), 1);
+}
@@ -0,0 +1,11 @@
+mod config;
+mod format;
+mod include;
+mod interpolate;
+mod load;
+
+pub(crate) use self::config::*;
+pub(crate) use self::format::*;
+pub(crate) use self::include::*;
+pub(crate) use self::interpolate::*;
+pub(crate) use self::load::*;
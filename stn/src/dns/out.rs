@@ -1,11 +1,39 @@
 use super::*;
-use crate::route::OutUdp;
+use crate::{config::*, route::OutUdp};
 use log::*;
 use parking_lot::{Mutex, RwLock};
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use serde::Deserialize;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Weak},
+    time::Duration,
+};
 use tokio::{sync::mpsc::channel, task::JoinHandle};
 use trust_dns_proto::op::{Message, MessageType, Query};
 
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct OutConfig {
+    pub(crate) tag: String,
+    #[serde(default = "default_server")]
+    pub(crate) server: Vec<String>,
+    #[serde(
+        default = "default_refresh_system",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub(crate) refresh_system: Duration,
+    #[serde(default = "default_udp_timeout", deserialize_with = "deserialize_secs")]
+    pub(crate) udp_timeout: Duration,
+    #[serde(default = "default_cache_size")]
+    pub(crate) cache_size: usize,
+    #[serde(default)]
+    pub(crate) refresh_cache: bool,
+    #[serde(default = "default_min_ttl")]
+    pub(crate) min_ttl: u32,
+    #[serde(default = "default_max_ttl")]
+    pub(crate) max_ttl: u32,
+}
+
 pub(crate) struct Out {
     pub(crate) tag: String,
     pub(crate) server: Arc<RwLock<Vec<SocketAddr>>>,
@@ -16,35 +44,32 @@ pub(crate) struct Out {
 }
 
 impl Out {
-    pub(crate) fn new(root: &serde_json::Value) -> Arc<dyn crate::route::Out + Send + Sync> {
-        let server = get_server_and_refresh_system(&root);
+    pub(crate) fn new(config: &OutConfig) -> Arc<dyn crate::route::Out + Send + Sync> {
+        let server =
+            get_server_and_refresh_system(&config.tag, &config.server, config.refresh_system);
 
         let out = Arc::new(Self {
-            tag: root["tag"].as_str().expect("tag not found").to_string(),
+            tag: config.tag.clone(),
             server,
-            udp_timeout: Duration::from_nanos(
-                (root["udp_timeout"].as_f64().unwrap_or_else(|| 60f64) * 1000_000_000f64) as u64,
-            ),
-            min_ttl: root["min_ttl"].as_u64().unwrap_or_else(|| 60) as _,
-            max_ttl: root["max_ttl"].as_u64().unwrap_or_else(|| 2147483647) as _,
-            cache: Mutex::new(lru::LruCache::new(
-                root["cache_size"].as_u64().unwrap_or_else(|| 1024) as _,
-            )),
+            udp_timeout: config.udp_timeout,
+            min_ttl: config.min_ttl,
+            max_ttl: config.max_ttl,
+            cache: Mutex::new(lru::LruCache::new(config.cache_size)),
         });
 
         // refresh_cache
-        if root["refresh_cache"].as_bool().unwrap_or_else(|| false) {
-            tokio::spawn(out.clone().refresh_cache());
+        if config.refresh_cache {
+            tokio::spawn(Self::refresh_cache(Arc::downgrade(&out)));
         }
 
         out
     }
 
-    async fn refresh_cache(self: Arc<Self>) {
-        let interval = if self.min_ttl == 0 {
-            return;
-        } else {
-            Duration::from_secs(self.min_ttl as _)
+    // hold Weak, so the out can be dropped after reload
+    async fn refresh_cache(out: Weak<Self>) {
+        let interval = match out.upgrade() {
+            Some(s) if s.min_ttl != 0 => Duration::from_secs(s.min_ttl as _),
+            _ => return,
         };
 
         let mut tasks: Vec<JoinHandle<()>> = Vec::new();
@@ -58,9 +83,14 @@ impl Out {
             }
             tasks.clear();
 
+            let this = match out.upgrade() {
+                Some(s) => s,
+                None => return,
+            };
+
             // get refresh list
             let mut queries = Vec::new();
-            for (k, (_, deadline)) in &*self.cache.lock() {
+            for (k, (_, deadline)) in &*this.cache.lock() {
                 if tokio::time::Instant::now() + interval > *deadline {
                     queries.push(k.clone());
                 }
@@ -68,7 +98,7 @@ impl Out {
 
             // delete cache
             {
-                let mut cache_lock = self.cache.lock();
+                let mut cache_lock = this.cache.lock();
                 for query in &queries {
                     cache_lock.pop(query);
                 }
@@ -88,14 +118,14 @@ impl Out {
                 // generate saddr
                 let saddr = format!(
                     "{}_refresh_cache:{}",
-                    self.tag,
+                    this.tag,
                     query.as_ptr() as *const usize as usize,
                 );
 
                 // new a udp
                 let (client_tx, mut server_rx) = channel(100);
                 let (server_tx, client_rx) = channel(100);
-                self.clone()
+                this.clone()
                     .udp_bind(saddr.clone(), client_tx, client_rx)
                     .await
                     .unwrap();
@@ -104,7 +134,7 @@ impl Out {
                 tasks.push(tokio::spawn({
                     let server_tx = server_tx.clone(); // keep connection
                     async move {
-                        while let Some(_) = server_rx.recv().await {}
+                        while server_rx.recv().await.is_some() {}
                         debug!("close");
                         let _ = server_tx;
                     }
@@ -126,12 +156,13 @@ impl Out {
                     let buf = buf.clone();
                     let server_tx = server_tx.clone();
                     async move {
-                        if let Err(_) = server_tx
+                        if server_tx
                             .send((
                                 "0.0.0.0:0".to_string(), // "0.0.0.0:0" is ok, just refresh cache
                                 buf,
                             ))
                             .await
+                            .is_err()
                         {
                             debug!("close");
                         }
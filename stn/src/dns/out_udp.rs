@@ -16,7 +16,7 @@ impl crate::route::OutUdp for super::Out {
         // send to multi daddr, only the first recv data send to client
         let multi_daddr_map = Arc::new(dashmap::DashMap::<Vec<Query>, String>::new());
 
-        tokio::spawn(async move {
+        crate::route::spawn_in_span(async move {
             match bidirectional_with_timeout!(
                 {
                     // read client
@@ -35,6 +35,7 @@ impl crate::route::OutUdp for super::Out {
                             }
                             None => None,
                         };
+                    crate::metrics::count_dns_cache(&self.tag, lru_cache_value_option.is_some());
                     if let Some((mut message, _)) = lru_cache_value_option {
                         // fake id
                         message.set_id(dns_msg.id());
@@ -103,6 +104,7 @@ impl crate::route::OutUdp for super::Out {
             ) {
                 (Err(e), _, _) | (_, _, Err(e)) | (_, Err(e), _) => {
                     let e = e.to_string();
+                    crate::route::report_out_close(&saddr, &e);
                     if e.as_str() == "close" || e.as_str() == "timeout" {
                         debug!("{} {} {}", self.tag, saddr, e)
                     } else {
@@ -1,27 +1,63 @@
 use log::*;
 use parking_lot::RwLock;
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use serde_json::json;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::time::sleep;
 
+pub(crate) fn default_server() -> Vec<String> {
+    vec!["system".to_string()]
+}
+
+pub(crate) fn default_refresh_system() -> Duration {
+    Duration::from_secs(3)
+}
+
+pub(crate) fn default_cache_size() -> usize {
+    1024
+}
+
+pub(crate) fn default_min_ttl() -> u32 {
+    60
+}
+
+pub(crate) fn default_max_ttl() -> u32 {
+    2147483647
+}
+
+// "system", "8.8.8.8:53" or "8.8.8.8"
+pub(crate) fn check_server(server: &str) -> Result<(), String> {
+    if server == "system"
+        || server.parse::<SocketAddr>().is_ok()
+        || server.parse::<IpAddr>().is_ok()
+    {
+        Ok(())
+    } else {
+        Err(format!("invalid server {}", server))
+    }
+}
+
+// tag of the dns out or resolve, for the dns_server hook
 pub(crate) fn get_server_and_refresh_system(
-    root: &serde_json::Value,
+    tag: &str,
+    server: &[String],
+    refresh_interval: Duration,
 ) -> Arc<RwLock<Vec<SocketAddr>>> {
     let shared_server = Arc::new(RwLock::new(Vec::new()));
 
-    let mut server: Vec<String> = if let Some(s) = root["server"].as_array() {
-        s.iter()
-            .map(|x| {
-                let x_str = x.as_str().expect("server not string");
-                if x_str.parse::<SocketAddr>().is_ok() || x_str == "system" {
-                    x_str.to_string()
-                } else {
-                    format!("{}:53", x_str)
-                }
-            })
-            .collect()
-    } else {
-        vec!["system".to_string()]
-    };
+    let mut server: Vec<String> = server
+        .iter()
+        .map(|x| {
+            if x.parse::<SocketAddr>().is_ok() || x == "system" {
+                x.to_string()
+            } else {
+                format!("{}:53", x)
+            }
+        })
+        .collect();
 
     if server.contains(&"system".to_string()) {
         server.retain(|x| x.as_str() != "system");
@@ -34,19 +70,33 @@ pub(crate) fn get_server_and_refresh_system(
         if let Err(e) = refresh_system(server.clone(), &shared_server) {
             warn!("{}", e);
         };
-        let interval = Duration::from_nanos(
-            (root["refresh_system"].as_f64().unwrap_or_else(|| 3f64) * 1000_000_000f64) as _,
-        );
+        let interval = refresh_interval;
         if interval != Duration::new(0, 0) {
             tokio::spawn({
+                let tag = tag.to_string();
                 let server = server.clone();
-                let shared_server = shared_server.clone();
+                // stop refreshing once the owner is dropped
+                let shared_server = Arc::downgrade(&shared_server);
                 async move {
                     loop {
                         sleep(interval).await;
+                        let shared_server = match shared_server.upgrade() {
+                            Some(s) => s,
+                            None => break,
+                        };
+                        let old = shared_server.read().clone();
                         if let Err(e) = refresh_system(server.clone(), &shared_server) {
                             warn!("{}", e);
+                            continue;
                         };
+                        let new = shared_server.read().clone();
+                        if new != old {
+                            info!("{} dns server {:?} -> {:?}", tag, old, new);
+                            crate::hook::emit(
+                                "dns_server",
+                                || json!({ "tag": tag, "old": old, "new": new }),
+                            );
+                        }
                     }
                 }
             });
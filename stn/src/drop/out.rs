@@ -1,14 +1,21 @@
 use log::*;
+use serde::Deserialize;
 use std::sync::Arc;
 
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct OutConfig {
+    pub(crate) tag: String,
+}
+
 pub(crate) struct Out {
     pub(crate) tag: String,
 }
 
 impl Out {
-    pub(crate) fn new(root: &serde_json::Value) -> Arc<dyn crate::route::Out + Send + Sync> {
+    pub(crate) fn new(config: &OutConfig) -> Arc<dyn crate::route::Out + Send + Sync> {
         Arc::new(Out {
-            tag: root["tag"].as_str().expect("tag not found").to_string(),
+            tag: config.tag.clone(),
         })
     }
 }
@@ -0,0 +1,226 @@
+use crate::config::deserialize_secs;
+use dashmap::DashMap;
+use log::*;
+use parking_lot::RwLock;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    process::Stdio,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    process::Command,
+    time::timeout,
+};
+
+pub(crate) const EVENTS: [&str; 4] = ["connect_failures", "dns_server", "quota", "reload"];
+
+// a command or a post which takes longer is abandoned
+const HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static::lazy_static! {
+    static ref HOOK: RwLock<Option<Arc<HookConfig>>> = RwLock::new(None);
+    // out tag -> failures of the current window
+    static ref FAILURES: DashMap<String, Failures> = DashMap::new();
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct HookConfig {
+    // run with the payload on stdin
+    #[serde(default)]
+    pub(crate) command: Vec<String>,
+    // "http://127.0.0.1:8080/stn", the payload is posted to it
+    #[serde(default)]
+    pub(crate) url: String,
+    // empty for all events
+    #[serde(default)]
+    pub(crate) events: Vec<String>,
+    // failures of one out within connect_failures_window
+    #[serde(default = "default_connect_failures")]
+    pub(crate) connect_failures: u32,
+    #[serde(
+        default = "default_connect_failures_window",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub(crate) connect_failures_window: Duration,
+}
+
+fn default_connect_failures() -> u32 {
+    10
+}
+
+fn default_connect_failures_window() -> Duration {
+    Duration::from_secs(60)
+}
+
+impl HookConfig {
+    fn wants(&self, event: &str) -> bool {
+        self.events.is_empty() || self.events.iter().any(|x| x == event)
+    }
+}
+
+struct Failures {
+    start: Instant,
+    count: u32,
+}
+
+// setting.hook, needs a restart like other settings
+pub(crate) fn init(config: &Option<HookConfig>) {
+    *HOOK.write() = config.clone().map(Arc::new);
+}
+
+// run the hook in the background, payload is only built when the event is wanted
+pub(crate) fn emit(event: &'static str, payload: impl FnOnce() -> Value) {
+    let hook = match HOOK.read().as_ref() {
+        Some(s) if s.wants(event) => s.clone(),
+        _ => return,
+    };
+
+    let mut payload = payload();
+    payload["event"] = json!(event);
+    payload["time"] = json!(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default());
+    let payload = payload.to_string();
+
+    if !hook.command.is_empty() {
+        let hook = hook.clone();
+        let payload = payload.clone();
+        tokio::spawn(async move {
+            match timeout(HOOK_TIMEOUT, run_command(&hook.command, event, &payload)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => warn!("hook {} {:?} {}", event, hook.command, e),
+                Err(_) => warn!("hook {} {:?} timeout", event, hook.command),
+            }
+        });
+    }
+    if !hook.url.is_empty() {
+        tokio::spawn(async move {
+            match timeout(HOOK_TIMEOUT, post(&hook.url, &payload)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => warn!("hook {} {} {}", event, hook.url, e),
+                Err(_) => warn!("hook {} {} timeout", event, hook.url),
+            }
+        });
+    }
+}
+
+// once when an out reaches connect_failures within the window
+pub(crate) fn connect_failed(out: &str, network: &str, error: &str) {
+    let (threshold, window) = match HOOK.read().as_ref() {
+        Some(s) if s.wants("connect_failures") => (s.connect_failures, s.connect_failures_window),
+        _ => return,
+    };
+
+    let now = Instant::now();
+    let count = {
+        let mut failures = FAILURES.entry(out.to_string()).or_insert(Failures {
+            start: now,
+            count: 0,
+        });
+        if now.duration_since(failures.start) >= window {
+            failures.start = now;
+            failures.count = 0;
+        }
+        failures.count += 1;
+        failures.count
+    };
+
+    if count == threshold {
+        emit("connect_failures", || {
+            json!({
+                "out": out,
+                "network": network,
+                "failures": count,
+                "window": window.as_secs_f64(),
+                "error": error,
+            })
+        });
+    }
+}
+
+async fn run_command(
+    command: &[String],
+    event: &str,
+    payload: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut child = Command::new(&command[0])
+        .args(&command[1..])
+        .env("STN_EVENT", event)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(payload.as_bytes()).await?;
+    }
+    let status = child.wait().await?;
+    if !status.success() {
+        Err(status.to_string())?
+    }
+
+    Ok(())
+}
+
+async fn post(url: &str, payload: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let (host, path) = parse_url(url)?;
+
+    let mut stream = TcpStream::connect(host).await?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        payload.len(),
+        payload
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    stream.take(8192).read_to_end(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+    let status = response
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .split(' ')
+        .nth(1)
+        .unwrap_or_default();
+    if !status.starts_with('2') {
+        Err(format!("status {}", status))?
+    }
+
+    Ok(())
+}
+
+// "http://127.0.0.1:8080/stn" -> ("127.0.0.1:8080", "/stn")
+pub(crate) fn parse_url(url: &str) -> Result<(&str, &str), String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("invalid url {}, expect http://", url))?;
+    let (host, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    if !host.contains(':') {
+        Err(format!("invalid url {}, expect a port", url))?
+    }
+
+    Ok((host, path))
+}
+
+#[test]
+fn test_parse_url() {
+    assert_eq!(
+        parse_url("http://127.0.0.1:8080/stn/events"),
+        Ok(("127.0.0.1:8080", "/stn/events"))
+    );
+    assert_eq!(parse_url("http://localhost:80"), Ok(("localhost:80", "/")));
+    assert!(parse_url("https://127.0.0.1:443/").is_err());
+    assert!(parse_url("http://127.0.0.1/").is_err());
+}
@@ -2,8 +2,8 @@ pub(crate) const TCP_LEN: usize = 8192;
 
 #[inline]
 pub(crate) fn is_http_response_successful(buf: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
-    if !String::from_utf8_lossy(&buf).contains("HTTP/1.1 200")
-        && !String::from_utf8_lossy(&buf).contains("HTTP/1.0 200")
+    if !String::from_utf8_lossy(buf).contains("HTTP/1.1 200")
+        && !String::from_utf8_lossy(buf).contains("HTTP/1.0 200")
     {
         Err("http response not succeeded")?
     }
@@ -1,55 +1,82 @@
-use crate::misc::{build_socket_listener, socketaddr_to_string};
+use crate::route::in_span;
+use crate::{
+    config::*,
+    misc::{build_socket_listener, socketaddr_to_string},
+};
 use log::*;
-use std::{sync::Arc, time::Duration};
+use serde::Deserialize;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tokio::net::TcpListener;
+use tracing::Instrument;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct InConfig {
+    pub(crate) tag: String,
+    pub(crate) address: SocketAddr,
+    #[serde(default = "default_true")]
+    pub(crate) tcp_nodelay: bool,
+    #[serde(
+        default = "default_tcp_keepalive_interval",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub(crate) tcp_keepalive_interval: Duration,
+    #[serde(default = "default_tcp_timeout", deserialize_with = "deserialize_secs")]
+    pub(crate) tcp_timeout: Duration,
+}
 
 pub(crate) struct In {
     pub(crate) tag: String,
     pub(crate) tcp_nodelay: bool,
-    pub(crate) tcp_keepalive_inverval: Duration,
+    pub(crate) tcp_keepalive_interval: Duration,
     pub(crate) tcp_timeout: Duration,
-    pub(crate) tcp_listener: TcpListener,
 }
 
 impl In {
-    pub(crate) async fn start(root: serde_json::Value) {
-        let bind_addr = root["address"].as_str().expect("address not found");
+    pub(crate) async fn start(config: InConfig) {
+        let bind_addr = config.address.to_string();
+        let tag = Some(config.tag.as_str());
+        // owned by the accept loop, so it closes when accepting stops
+        let tcp_listener = TcpListener::from_std(
+            build_socket_listener("tcp", &bind_addr, tag)
+                .unwrap()
+                .into(),
+        )
+        .unwrap();
 
         let r#in = Arc::new(In {
-            tag: root["tag"].as_str().expect("tag not found").to_string(),
-            tcp_nodelay: root["tcp_nodelay"].as_bool().unwrap_or_else(|| true),
-            tcp_keepalive_inverval: Duration::from_nanos(
-                (root["tcp_keepalive_inverval"]
-                    .as_f64()
-                    .unwrap_or_else(|| 30f64)
-                    * 1000_000_000f64) as u64,
-            ),
-            tcp_timeout: Duration::from_nanos(
-                (root["tcp_timeout"].as_f64().unwrap_or_else(|| 300f64) * 1000_000_000f64) as u64,
-            ),
-            tcp_listener: TcpListener::from_std(
-                build_socket_listener("tcp", bind_addr).unwrap().into(),
-            )
-            .unwrap(),
+            tag: config.tag.clone(),
+            tcp_nodelay: config.tcp_nodelay,
+            tcp_keepalive_interval: config.tcp_keepalive_interval,
+            tcp_timeout: config.tcp_timeout,
         });
 
-        tokio::spawn(r#in.clone().listen());
+        tokio::spawn(r#in.clone().listen(tcp_listener));
     }
 
-    async fn listen(self: Arc<Self>) {
+    async fn listen(self: Arc<Self>, tcp_listener: TcpListener) {
         loop {
-            let (client, saddr) = match self.tcp_listener.accept().await {
+            let accept = tokio::select! {
+                r = tcp_listener.accept() => r,
+                // stop accepting new flows
+                _ = crate::shutdown::wait_shutdown() => return,
+            };
+            let (client, saddr) = match accept {
                 Ok(o) => o,
                 Err(e) => {
                     warn!("{}", e);
                     continue;
                 }
             };
+            crate::metrics::inc(
+                "stn_in_accepted_total",
+                &[("in", &self.tag), ("network", "tcp")],
+            );
 
             if let Err(e) = crate::misc::set_nodelay_keepalive_interval(
                 &client,
                 self.tcp_nodelay,
-                self.tcp_keepalive_inverval,
+                self.tcp_keepalive_interval,
             ) {
                 warn!("{} {} {}", self.tag, saddr, e);
                 continue;
@@ -72,6 +99,7 @@ impl In {
                         }
                     }
                 }
+                .instrument(in_span(&self.tag, &socketaddr_to_string(&saddr)))
             });
         }
     }
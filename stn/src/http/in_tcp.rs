@@ -16,18 +16,24 @@ impl super::In {
     where
         T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     {
-        let (client, daddr) = Stream::new(client).await?;
+        let (mut client, daddr) = Stream::new(client).await?;
 
-        // connect
-        let (mut client_rx, mut client_tx) = tokio::io::split(client);
-        let (server_tx, mut server_rx) = timeout(
+        // connect, then tell the client whether it succeeded
+        let connected = match timeout(
             self.tcp_timeout,
             crate::route::tcp_connect(self.tag.clone(), saddr.clone(), daddr.clone()),
         )
-        .await??;
+        .await
+        {
+            Ok(o) => o.map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        client.response(connected.is_ok()).await?;
+        let (server_tx, mut server_rx) = connected?;
+        let (mut client_rx, mut client_tx) = tokio::io::split(client);
 
         let mut buf = vec![0; TCP_LEN];
-        tokio::spawn(async move {
+        crate::route::spawn_in_span(async move {
             match bidirectional_with_timeout!(
                 {
                     // read client
@@ -56,6 +62,7 @@ impl super::In {
                 // client or timeout error
                 (Err(e), _, _) | (_, _, Err(e)) => {
                     let e = e.to_string();
+                    crate::route::report_close(&self.tag, &saddr, &e);
                     if e.as_str() == "close" {
                         debug!("{} {} -> {} {}", self.tag, saddr, daddr, e)
                     } else {
@@ -65,6 +72,7 @@ impl super::In {
                 // server error
                 (_, Err(e), _) => {
                     let e = e.to_string();
+                    crate::route::report_close(&self.tag, &saddr, &e);
                     if e.as_str() == "close" {
                         debug!("{} {} -> {} {}", self.tag, daddr, saddr, e)
                     } else {
@@ -1,138 +1,284 @@
+// "route/route.rs" like modules and Out::new returning Arc<dyn Out> are the layout of the repo
+#![allow(clippy::module_inception, clippy::new_ret_no_self)]
+
 #[macro_use]
 mod route;
+mod access_log;
+mod accounting;
+mod admin;
+mod check;
+mod config;
 mod dns;
 mod drop;
+mod hook;
 mod http;
+mod metrics;
 mod misc;
 mod origin;
+mod privilege;
+mod reload;
 mod resolve;
+mod shutdown;
 mod socks5;
 #[cfg(feature = "private")]
 mod stn;
 #[cfg(not(target_os = "windows"))]
+mod systemd;
+#[cfg(not(target_os = "windows"))]
 mod tproxy;
 
+use config::{Config, Format, InConfig, LogFormat, LogLevel, SettingConfig};
 use log::*;
-use log4rs::{
-    append::{console::ConsoleAppender, rolling_file::policy::compound},
-    config,
-};
 use std::{env, fs::File, io::prelude::*};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+
+    // --format works with every subcommand
+    let format = match take_format(&mut args) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
-    if args.len() != 3 || args[1].as_str() != "-c" {
-        show_help();
-        return Ok(());
+    match (args.len(), args.get(1).map(|x| x.as_str())) {
+        (3, Some("-c")) => {}
+        (_, Some("accounting")) => {
+            if let Err(e) = accounting::query(&args[2..], format) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        (_, Some("route")) => {
+            if let Err(e) = route::route_trace(&args[2..], format) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        (4, Some("check")) if args[2].as_str() == "-c" => {
+            if !check::check(args[3].as_str(), format) {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        _ => {
+            show_help();
+            return Ok(());
+        }
     }
 
-    let file = File::open(args[2].as_str())?;
-    let stripped = json_comments::StripComments::new(file);
-    let root: serde_json::Value = serde_json::from_reader(stripped)?;
+    // before daemon() changes the pid
+    #[cfg(not(target_os = "windows"))]
+    systemd::init();
+
+    let config = match config::load(args[2].as_str(), format) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // route files are read before privileges are dropped, and errors are shown before daemon()
+    let parsed = match route::route_parse(&config) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
-    do_setting(&root["setting"])?;
+    do_setting(&config.setting)?;
     info!("setting done");
 
+    let pid_file = config.setting.pid_file.clone();
+
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()?
-        .block_on(tokio_based_start(&root));
+        .block_on(tokio_based_start(args[2].clone(), format, config, parsed));
+
+    access_log::stop();
+
+    if !pid_file.is_empty() {
+        if let Err(e) = std::fs::remove_file(&pid_file) {
+            warn!("{} {}", pid_file, e);
+        }
+    }
+    info!("exit");
 
     Ok(())
 }
 
 // uid/gid iptables match
-async fn tokio_based_start(root: &serde_json::Value) {
-    resolve::init_resolve(&root["resolve"]);
+async fn tokio_based_start(
+    config_path: String,
+    format: Option<Format>,
+    config: Config,
+    parsed: route::ParsedRoute,
+) {
+    resolve::init_resolve(&config.resolve);
     info!("resolve initialized");
 
-    route::route_out_parse(root);
+    route::out_parse(&config, parsed);
     info!("route and out initialized");
 
-    for iter in root["in"].as_array().expect("in not found") {
-        match iter["protocol"].as_str() {
-            Some("http") => tokio::spawn(http::In::start(iter.clone())),
-            Some("origin") => tokio::spawn(origin::In::start(iter.clone())),
-            Some("socks5") => tokio::spawn(socks5::In::start(iter.clone())),
+    if let Some(addr) = config.setting.metrics {
+        if let Err(e) = metrics::start(addr).await {
+            error!("metrics {} {}", addr, e);
+            eprintln!("metrics {} {}", addr, e);
+            std::process::exit(1);
+        }
+    }
+
+    if !config.setting.admin.is_empty() {
+        if let Err(e) = admin::start(&config.setting.admin).await {
+            error!("admin {} {}", config.setting.admin, e);
+            eprintln!("admin {} {}", config.setting.admin, e);
+            std::process::exit(1);
+        }
+    }
+
+    // start() returns once the listeners are bound
+    for r#in in config.r#in {
+        match r#in {
+            InConfig::Http(config) => http::In::start(config).await,
+            InConfig::Origin(config) => origin::In::start(config).await,
+            InConfig::Socks5(config) => socks5::In::start(config).await,
             #[cfg(feature = "private")]
-            Some("stn") => tokio::spawn(stn::In::start(iter.clone())),
+            InConfig::Stn(config) => {
+                tokio::spawn(stn::In::start(config));
+            }
             #[cfg(not(target_os = "windows"))]
-            Some("tproxy") => tokio::spawn(tproxy::In::start(iter.clone())),
-            protocol => panic!("protocol not support: {:?}", protocol),
+            InConfig::Tproxy(config) => tproxy::In::start(config).await,
         };
     }
     info!("in initialized");
 
-    // block forever
-    tokio::sync::Notify::new().notified().await;
+    #[cfg(not(target_os = "windows"))]
+    {
+        systemd::notify_ready();
+        tokio::spawn(systemd::watchdog());
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    tokio::spawn(reload::reload_on_sighup(config_path, format));
+
+    tokio::spawn(accounting::flush_every_minute());
+    if !config.setting.quota_state.is_empty() {
+        tokio::spawn(route::save_quota_state_every_minute(
+            config.setting.quota_state.clone(),
+        ));
+    }
+
+    // block until SIGTERM or SIGINT
+    shutdown::wait_signal().await;
+    shutdown::shutdown(config.setting.drain_timeout).await;
+    accounting::flush();
+    route::save_quota_state(&config.setting.quota_state);
 }
 
 fn show_help() {
     println!("stn version:{}", env!("CARGO_PKG_VERSION"));
-    println!("  -c [file]     specify the configuration file to start");
-    println!("  -h            show this message");
+    println!("  -c [file]            specify the configuration file to start");
+    println!("  check -c [file]      check the configuration file without starting");
+    println!("  route -c [file] --tag [tag] --daddr [addr]");
+    println!("        [--network tcp|udp] [--saddr [addr]] [--dns [domain]] [--dns-hex [hex]]");
+    println!("                       show which route and out a flow would take");
+    println!("  accounting -c [file] [--by hour|day] [--in [tag]] [--out [tag]] [--source [ip]]");
+    println!("                       show the traffic of setting.accounting");
+    println!("  --format [format]    json, toml or yaml, detected by extension by default");
+    println!("  -h                   show this message");
+}
+
+// remove "--format [format]" from args
+fn take_format(args: &mut Vec<String>) -> Result<Option<Format>, Box<dyn std::error::Error>> {
+    let index = match args.iter().position(|x| x == "--format") {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+    if index + 1 >= args.len() {
+        Err("--format needs a value")?
+    }
+
+    let format = args.remove(index + 1).parse::<Format>()?;
+    args.remove(index);
+
+    Ok(Some(format))
 }
 
-fn do_setting(root: &serde_json::Value) -> Result<(), Box<dyn std::error::Error>> {
+fn do_setting(setting: &SettingConfig) -> Result<(), Box<dyn std::error::Error>> {
+    // opened before changing uid
+    access_log::init(&setting.access_log, setting.access_log_max)?;
+    accounting::init(&setting.accounting)?;
+    route::load_quota_state(&setting.quota_state)?;
+    hook::init(&setting.hook);
+    route::init_sniff(&setting.sniff);
+
     #[cfg(not(target_os = "windows"))]
     unsafe {
-        if let Some(gid) = root["gid"].as_u64() {
+        if let Some(gid) = setting.gid {
             assert!(libc::setgid(gid as _) == 0);
         }
-        if let Some(uid) = root["uid"].as_u64() {
-            assert!(libc::setuid(uid as _) == 0);
+        if let Some(uid) = setting.uid {
+            privilege::setuid(uid, &setting.capabilities)?;
+        }
+        // a failed rotation would stop the log silently
+        for path in [&setting.access_log, &setting.log_file] {
+            if !path.is_empty() && path != "stdout" {
+                access_log::check_rotate(path)?;
+            }
         }
 
-        if let Some(true) = root["daemon"].as_bool() {
+        if setting.daemon {
             assert!(libc::daemon(1, 1) == 0);
         }
     }
 
-    let pid_file = root["pid_file"].as_str().unwrap_or_else(|| "");
-    if pid_file != "" {
-        let mut file = File::create(pid_file)?;
+    access_log::start();
+
+    if !setting.pid_file.is_empty() {
+        let mut file = File::create(&setting.pid_file)?;
         write!(file, "{}", std::process::id())?;
     }
 
-    let log_level = match root["log_level"].as_str() {
-        Some("debug") => LevelFilter::Debug,
-        Some("info") => LevelFilter::Info,
-        Some("warn") => LevelFilter::Warn,
-        Some("error") => LevelFilter::Error,
-        Some(_) => Err("unsupport log level")?,
-        None => LevelFilter::Error,
+    let log_level = match setting.log_level {
+        LogLevel::Debug => tracing::Level::DEBUG,
+        LogLevel::Info => tracing::Level::INFO,
+        LogLevel::Warn => tracing::Level::WARN,
+        LogLevel::Error => tracing::Level::ERROR,
     };
 
-    let log_encoder = Box::new(log4rs::encode::pattern::PatternEncoder::new(
-        "{d(%m-%d %H:%M:%S%.6f)}   {({l}):5}   {({f}:{L}):>30}   {m}{n}",
-    ));
-
-    let log_appender: Box<dyn log4rs::append::Append> = match root["log_file"].as_str() {
-        Some("") | Some("stdout") | None => {
-            Box::new(ConsoleAppender::builder().encoder(log_encoder).build())
-        }
-        Some(file) => {
-            let file_max = root["log_file_max"].as_u64().unwrap_or_else(|| 1024);
-            Box::new(
-                log4rs::append::rolling_file::RollingFileAppender::builder()
-                    .encoder(log_encoder)
-                    .build(
-                        file,
-                        Box::new(compound::CompoundPolicy::new(
-                            Box::new(compound::trigger::size::SizeTrigger::new(file_max * 1024)),
-                            Box::new(compound::roll::delete::DeleteRoller::new()),
-                        )),
-                    )?,
-            )
-        }
+    // rotated like access_log
+    let (log_writer, ansi) = match setting.log_file.as_str() {
+        "" | "stdout" => (BoxMakeWriter::new(std::io::stdout), true),
+        file => (
+            BoxMakeWriter::new(std::sync::Mutex::new(access_log::RotatingFile::open(
+                file,
+                setting.log_file_max * 1024,
+            )?)),
+            false,
+        ),
     };
 
-    let log_config = config::Config::builder()
-        .appender(config::Appender::builder().build("root", log_appender))
-        .build(config::Root::builder().appender("root").build(log_level))?;
-
-    log4rs::init_config(log_config)?;
+    // records of the log crate are bridged, with the spans of their flow
+    let log_builder = tracing_subscriber::fmt()
+        .with_max_level(log_level)
+        .with_writer(log_writer)
+        .with_ansi(ansi)
+        .with_target(false)
+        .with_file(true)
+        .with_line_number(true);
+    match setting.log_format {
+        LogFormat::Text => log_builder.try_init(),
+        LogFormat::Json => log_builder.json().with_span_list(true).try_init(),
+    }
+    .map_err(|e| e.to_string())?;
 
     Ok(())
 }
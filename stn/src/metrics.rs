@@ -0,0 +1,199 @@
+use crate::misc::{read_http_request, write_http_response};
+use dashmap::DashMap;
+use log::*;
+use std::{
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::net::TcpListener;
+
+// name, type, help
+const METRICS: [(&str, &str, &str); 6] = [
+    (
+        "stn_in_accepted_total",
+        "counter",
+        "Accepted tcp connections and new udp sources per in.",
+    ),
+    (
+        "stn_out_connect_total",
+        "counter",
+        "tcp_connect and udp_bind results per out.",
+    ),
+    (
+        "stn_bytes_total",
+        "counter",
+        "Payload bytes per previous tag, out and direction.",
+    ),
+    (
+        "stn_route_hits_total",
+        "counter",
+        "Tcp connections and udp packets matched by each route, reset on reload.",
+    ),
+    (
+        "stn_dns_cache_total",
+        "counter",
+        "Cache lookups of dns outs and resolve.",
+    ),
+    (
+        "stn_udp_associations",
+        "gauge",
+        "Active udp fullcone associations per tag.",
+    ),
+];
+
+lazy_static::lazy_static! {
+    // (name, rendered labels) -> value
+    static ref VALUES: DashMap<(&'static str, String), Arc<AtomicU64>> = DashMap::new();
+}
+
+// keep the handle to skip the lookup on every packet
+pub(crate) fn counter(name: &'static str, labels: &[(&str, &str)]) -> Arc<AtomicU64> {
+    VALUES
+        .entry((name, render_labels(labels)))
+        .or_default()
+        .clone()
+}
+
+pub(crate) fn inc(name: &'static str, labels: &[(&str, &str)]) {
+    counter(name, labels).fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn count_dns_cache(tag: &str, hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    inc("stn_dns_cache_total", &[("tag", tag), ("result", result)]);
+}
+
+// +1 while alive
+pub(crate) struct GaugeGuard(Arc<AtomicU64>);
+
+impl GaugeGuard {
+    pub(crate) fn new(name: &'static str, labels: &[(&str, &str)]) -> Self {
+        let gauge = counter(name, labels);
+        gauge.fetch_add(1, Ordering::Relaxed);
+        GaugeGuard(gauge)
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    let mut rendered = String::new();
+    for (index, (key, value)) in labels.iter().enumerate() {
+        if index != 0 {
+            rendered.push(',');
+        }
+        let value = value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        let _ = write!(rendered, "{}=\"{}\"", key, value);
+    }
+
+    rendered
+}
+
+// prometheus text format
+pub(crate) fn render() -> String {
+    let mut values: Vec<(&'static str, String, u64)> = VALUES
+        .iter()
+        .map(|x| {
+            (
+                x.key().0,
+                x.key().1.clone(),
+                x.value().load(Ordering::Relaxed),
+            )
+        })
+        .collect();
+    for (route, jump, hits) in crate::route::route_hits() {
+        values.push((
+            "stn_route_hits_total",
+            render_labels(&[("route", &route), ("jump", &jump)]),
+            hits,
+        ));
+    }
+    values.sort();
+
+    let mut text = String::new();
+    for (name, type_, help) in METRICS.iter() {
+        let _ = writeln!(text, "# HELP {} {}", name, help);
+        let _ = writeln!(text, "# TYPE {} {}", name, type_);
+        for (_, labels, value) in values.iter().filter(|x| x.0 == *name) {
+            let _ = writeln!(text, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+
+    text
+}
+
+// bind before READY=1, then serve GET /metrics
+pub(crate) async fn start(addr: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("metrics listen on {}", addr);
+
+    tokio::spawn(async move {
+        loop {
+            let (mut client, saddr) = match listener.accept().await {
+                Ok(o) => o,
+                Err(e) => {
+                    warn!("{}", e);
+                    continue;
+                }
+            };
+
+            tokio::spawn(async move {
+                let request = read_http_request(&mut client)
+                    .await
+                    .map_err(|e| e.to_string());
+                let result = match request {
+                    Ok((method, path, _)) if method == "GET" && path == "/metrics" => {
+                        write_http_response(
+                            &mut client,
+                            "200 OK",
+                            "text/plain; version=0.0.4",
+                            render().as_bytes(),
+                        )
+                        .await
+                    }
+                    Ok(_) => {
+                        write_http_response(&mut client, "404 Not Found", "text/plain", b"").await
+                    }
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = result {
+                    debug!("metrics {} {}", saddr, e);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+#[test]
+fn test_render() {
+    inc(
+        "stn_in_accepted_total",
+        &[("in", "test\"render"), ("network", "tcp")],
+    );
+    inc(
+        "stn_in_accepted_total",
+        &[("in", "test\"render"), ("network", "tcp")],
+    );
+    let guard = GaugeGuard::new("stn_udp_associations", &[("tag", "test_render")]);
+
+    let text = render();
+    assert!(text.contains("# TYPE stn_udp_associations gauge\n"));
+    assert!(text.contains("stn_in_accepted_total{in=\"test\\\"render\",network=\"tcp\"} 2\n"));
+    assert!(text.contains("stn_udp_associations{tag=\"test_render\"} 1\n"));
+
+    drop(guard);
+    assert!(render().contains("stn_udp_associations{tag=\"test_render\"} 0\n"));
+}
@@ -4,6 +4,10 @@ use std::{
     net::{SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs},
     time::Duration,
 };
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::timeout,
+};
 
 // "1.2.3.4:80" -> "1.2.3.4" 80
 pub(crate) fn split_addr_str(
@@ -41,11 +45,13 @@ pub(crate) fn socketaddr_to_string(addr: &SocketAddr) -> String {
     }
 }
 
-// if addr is ipv6, IPV6_V6ONLY will be disabled
+// if addr is ipv6, IPV6_V6ONLY will be disabled.
+// an in passes its tag to adopt the socket systemd passed with the same name
 #[inline]
 pub(crate) fn build_socket_listener(
     type_str: &str,
     bind_addr: &str,
+    tag: Option<&str>,
 ) -> Result<Socket, Box<dyn std::error::Error>> {
     let bind_addr: std::net::SocketAddr = bind_addr.parse()?;
 
@@ -55,6 +61,15 @@ pub(crate) fn build_socket_listener(
         type_ => Err(format!("{} not support", type_))?,
     };
 
+    #[cfg(not(target_os = "windows"))]
+    if let Some(tag) = tag {
+        if let Some(listener) = crate::systemd::take_listen_fd(tag, type_) {
+            log::info!("{} {} adopt inherited socket", tag, type_str);
+            listener.set_nonblocking(true)?;
+            return Ok(listener);
+        }
+    }
+
     let listener = if bind_addr.is_ipv4() {
         socket2::Socket::new(socket2::Domain::IPV4, type_, None)?
     } else {
@@ -122,6 +137,86 @@ pub(crate) fn set_nodelay_keepalive_interval(
     Ok(())
 }
 
+// request line and host header of a small http request, the body is ignored
+pub(crate) async fn read_http_request<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<(String, String, Option<String>), Box<dyn std::error::Error>> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+
+    timeout(Duration::from_secs(5), async {
+        while !buf.windows(4).any(|x| x == b"\r\n\r\n") {
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 || buf.len() + n > 8192 {
+                Err("invalid http request")?
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        Ok::<(), Box<dyn std::error::Error>>(())
+    })
+    .await
+    .or(Err("timeout"))??;
+
+    let head = String::from_utf8_lossy(&buf);
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+    let host = lines.find_map(|x| {
+        let (name, value) = x.split_once(':')?;
+        name.eq_ignore_ascii_case("host")
+            .then(|| value.trim().to_string())
+    });
+
+    Ok((method, path, host))
+}
+
+pub(crate) async fn write_http_response<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+// days since 1970-01-01 to (year, month, day), from http://howardhinnant.github.io/date_algorithms.html
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month as u32, day as u32)
+}
+
+// the inverse of civil_from_days
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146097 + doe - 719468
+}
+
 #[inline]
 pub(crate) fn is_valid_domain(domain: &str) -> bool {
     lazy_static::lazy_static! {
@@ -132,12 +227,50 @@ pub(crate) fn is_valid_domain(domain: &str) -> bool {
     RE.is_match(domain)
 }
 
+// a temp directory of a test, removed with its files on drop
+#[cfg(test)]
+pub(crate) struct TestDir(pub(crate) std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub(crate) fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("stn_test_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        TestDir(dir)
+    }
+
+    // path of name in the directory, written with contents
+    pub(crate) fn write(&self, name: &str, contents: impl AsRef<[u8]>) -> String {
+        let path = self.0.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().to_string()
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn test_civil() {
+    assert_eq!(civil_from_days(0), (1970, 1, 1));
+    assert_eq!(civil_from_days(18690), (2021, 3, 4));
+    assert_eq!(days_from_civil(2021, 3, 4), 18690);
+    assert_eq!(days_from_civil(2024, 2, 29), 19782);
+    assert_eq!(civil_from_days(19782), (2024, 2, 29));
+}
+
 #[test]
 fn test_valid_domain() {
-    assert_eq!(is_valid_domain("a.com"), true);
-    assert_eq!(is_valid_domain("a.com."), true);
-    assert_eq!(is_valid_domain("a..com"), false);
-    assert_eq!(is_valid_domain(".a.com"), false);
-    assert_eq!(is_valid_domain("a.c"), false);
-    assert_eq!(is_valid_domain("a"), false);
+    assert!(is_valid_domain("a.com"));
+    assert!(is_valid_domain("a.com."));
+    assert!(!is_valid_domain("a..com"));
+    assert!(!is_valid_domain(".a.com"));
+    assert!(!is_valid_domain("a.c"));
+    assert!(!is_valid_domain("a"));
 }
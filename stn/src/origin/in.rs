@@ -1,52 +1,67 @@
-use crate::misc::build_socket_listener;
-use std::{sync::Arc, time::Duration};
+use crate::{config::*, misc::build_socket_listener};
+use serde::Deserialize;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tokio::{
     net::{TcpListener, UdpSocket},
     sync::mpsc::Sender,
 };
 
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct InConfig {
+    pub(crate) tag: String,
+    pub(crate) address: SocketAddr,
+    #[serde(default = "default_true")]
+    pub(crate) tcp_nodelay: bool,
+    #[serde(
+        default = "default_tcp_keepalive_interval",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub(crate) tcp_keepalive_interval: Duration,
+    #[serde(default = "default_tcp_timeout", deserialize_with = "deserialize_secs")]
+    pub(crate) tcp_timeout: Duration,
+    #[serde(default = "default_udp_timeout", deserialize_with = "deserialize_secs")]
+    pub(crate) udp_timeout: Duration,
+}
+
 pub(crate) struct In {
     pub(crate) tag: String,
     pub(crate) tcp_nodelay: bool,
-    pub(crate) tcp_keepalive_inverval: Duration,
+    pub(crate) tcp_keepalive_interval: Duration,
     pub(crate) tcp_timeout: Duration,
     pub(crate) udp_timeout: Duration,
-    pub(crate) tcp_listener: TcpListener,
     pub(crate) udp_listener: UdpSocket,
     pub(crate) fullcone_map: dashmap::DashMap<String, Sender<Vec<u8>>>,
 }
 
 impl In {
-    pub(crate) async fn start(root: serde_json::Value) {
-        let bind_addr = root["address"].as_str().expect("address not found");
+    pub(crate) async fn start(config: InConfig) {
+        let bind_addr = config.address.to_string();
+        let tag = Some(config.tag.as_str());
+        // owned by the accept loop, so it closes when accepting stops
+        let tcp_listener = TcpListener::from_std(
+            build_socket_listener("tcp", &bind_addr, tag)
+                .unwrap()
+                .into(),
+        )
+        .unwrap();
 
         let r#in = Arc::new(In {
-            tag: root["tag"].as_str().expect("tag not found").to_string(),
-            tcp_nodelay: root["tcp_nodelay"].as_bool().unwrap_or_else(|| true),
-            tcp_keepalive_inverval: Duration::from_nanos(
-                (root["tcp_keepalive_inverval"]
-                    .as_f64()
-                    .unwrap_or_else(|| 30f64)
-                    * 1000_000_000f64) as u64,
-            ),
-            tcp_timeout: Duration::from_nanos(
-                (root["tcp_timeout"].as_f64().unwrap_or_else(|| 300f64) * 1000_000_000f64) as u64,
-            ),
-            udp_timeout: Duration::from_nanos(
-                (root["udp_timeout"].as_f64().unwrap_or_else(|| 60f64) * 1000_000_000f64) as u64,
-            ),
-            tcp_listener: TcpListener::from_std(
-                build_socket_listener("tcp", bind_addr).unwrap().into(),
-            )
-            .unwrap(),
+            tag: config.tag.clone(),
+            tcp_nodelay: config.tcp_nodelay,
+            tcp_keepalive_interval: config.tcp_keepalive_interval,
+            tcp_timeout: config.tcp_timeout,
+            udp_timeout: config.udp_timeout,
             udp_listener: UdpSocket::from_std(
-                build_socket_listener("udp", bind_addr).unwrap().into(),
+                build_socket_listener("udp", &bind_addr, tag)
+                    .unwrap()
+                    .into(),
             )
             .unwrap(),
             fullcone_map: dashmap::DashMap::new(),
         });
 
-        tokio::spawn(r#in.clone().tcp_start());
+        tokio::spawn(r#in.clone().tcp_start(tcp_listener));
         tokio::spawn(r#in.clone().udp_start());
     }
 }
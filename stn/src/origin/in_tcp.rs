@@ -1,28 +1,39 @@
 use super::*;
 use crate::misc::socketaddr_to_string;
+use crate::route::in_span;
 use log::*;
 use std::sync::Arc;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    net::{TcpListener, TcpStream},
     time::timeout,
 };
+use tracing::Instrument;
 
 impl super::In {
-    pub(crate) async fn tcp_start(self: Arc<Self>) {
+    pub(crate) async fn tcp_start(self: Arc<Self>, tcp_listener: TcpListener) {
         loop {
-            let (client, saddr) = match self.tcp_listener.accept().await {
+            let accept = tokio::select! {
+                r = tcp_listener.accept() => r,
+                // stop accepting new flows
+                _ = crate::shutdown::wait_shutdown() => return,
+            };
+            let (client, saddr) = match accept {
                 Ok(o) => o,
                 Err(e) => {
                     warn!("{}", e);
                     continue;
                 }
             };
+            crate::metrics::inc(
+                "stn_in_accepted_total",
+                &[("in", &self.tag), ("network", "tcp")],
+            );
 
             if let Err(e) = crate::misc::set_nodelay_keepalive_interval(
                 &client,
                 self.tcp_nodelay,
-                self.tcp_keepalive_inverval,
+                self.tcp_keepalive_interval,
             ) {
                 warn!("{} {} {}", self.tag, saddr, e);
                 continue;
@@ -40,6 +51,7 @@ impl super::In {
                         warn!("{} {} {}", self_clone.tag, saddr, e);
                     }
                 }
+                .instrument(in_span(&self.tag, &socketaddr_to_string(&saddr)))
             });
         }
     }
@@ -56,11 +68,11 @@ impl super::In {
         let (mut client_rx, mut client_tx) = client.into_split();
         let (server_tx, mut server_rx) = timeout(
             self.tcp_timeout,
-            crate::route::tcp_connect(self.tag.clone(), saddr.clone(), daddr.clone()),
+            crate::route::tcp_connect_early(self.tag.clone(), saddr.clone(), daddr.clone()),
         )
         .await??;
 
-        tokio::spawn(async move {
+        crate::route::spawn_in_span(async move {
             let mut buf = vec![0; TCP_LEN];
             match bidirectional_with_timeout!(
                 {
@@ -90,6 +102,7 @@ impl super::In {
                 // client or timeout error
                 (Err(e), _, _) | (_, _, Err(e)) => {
                     let e = e.to_string();
+                    crate::route::report_close(&self.tag, &saddr, &e);
                     if e.as_str() == "close" {
                         debug!("{} {} -> {} {}", self.tag, saddr, daddr, e)
                     } else {
@@ -99,6 +112,7 @@ impl super::In {
                 // server error
                 (_, Err(e), _) => {
                     let e = e.to_string();
+                    crate::route::report_close(&self.tag, &saddr, &e);
                     if e.as_str() == "close" {
                         debug!("{} {} -> {} {}", self.tag, daddr, saddr, e)
                     } else {
@@ -1,16 +1,19 @@
 use super::*;
 use crate::misc::socketaddr_to_string;
+use crate::route::in_span;
 use log::*;
 use std::sync::Arc;
 use tokio::sync::mpsc::{channel, Receiver};
+use tracing::Instrument;
 
 impl In {
     pub(crate) async fn udp_start(self: Arc<Self>) {
         let mut buf = vec![0u8; UDP_LEN];
 
         loop {
-            // recv
-            let (nrecv, saddr) = match self.udp_listener.recv_from(&mut buf).await {
+            // recv, known sources are still served while shutdown drains
+            let recv = self.udp_listener.recv_from(&mut buf).await;
+            let (nrecv, saddr) = match recv {
                 Ok(o) => o,
                 Err(e) => {
                     info!("{}", e);
@@ -22,10 +25,21 @@ impl In {
             // get server_tx or new a task
             let server_tx = if let Some(s) = self.fullcone_map.get(&saddr) {
                 s.value().clone()
+            } else if crate::shutdown::is_shutdown() {
+                // stop accepting new flows
+                continue;
             } else {
                 let (own_tx, own_rx) = channel(100);
+                crate::metrics::inc(
+                    "stn_in_accepted_total",
+                    &[("in", &self.tag), ("network", "udp")],
+                );
                 self.fullcone_map.insert(saddr.clone(), own_tx.clone());
-                tokio::spawn(self.clone().handle_udp(saddr.clone(), own_rx));
+                tokio::spawn(
+                    self.clone()
+                        .handle_udp(saddr.clone(), own_rx)
+                        .instrument(in_span(&self.tag, &saddr)),
+                );
                 own_tx
             };
 
@@ -51,7 +65,7 @@ impl In {
                 }
             };
 
-        tokio::spawn(async move {
+        crate::route::spawn_in_span(async move {
             match bidirectional_with_timeout!(
                 {
                     // read client
@@ -76,6 +90,7 @@ impl In {
             ) {
                 (Err(e), _, _) | (_, _, Err(e)) | (_, Err(e), _) => {
                     let e = e.to_string();
+                    crate::route::report_close(&self.tag, &saddr, &e);
                     if e.as_str() == "close" || e.as_str() == "timeout" {
                         debug!("{} {} {}", self.tag, saddr, e)
                     } else {
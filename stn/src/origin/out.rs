@@ -1,30 +1,40 @@
+use crate::config::*;
+use serde::Deserialize;
 use std::{sync::Arc, time::Duration};
 
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct OutConfig {
+    pub(crate) tag: String,
+    #[serde(default = "default_true")]
+    pub(crate) tcp_nodelay: bool,
+    #[serde(
+        default = "default_tcp_keepalive_interval",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub(crate) tcp_keepalive_interval: Duration,
+    #[serde(default = "default_tcp_timeout", deserialize_with = "deserialize_secs")]
+    pub(crate) tcp_timeout: Duration,
+    #[serde(default = "default_udp_timeout", deserialize_with = "deserialize_secs")]
+    pub(crate) udp_timeout: Duration,
+}
+
 pub(crate) struct Out {
     pub(crate) tag: String,
     pub(crate) tcp_nodelay: bool,
-    pub(crate) tcp_keepalive_inverval: Duration,
+    pub(crate) tcp_keepalive_interval: Duration,
     pub(crate) tcp_timeout: Duration,
     pub(crate) udp_timeout: Duration,
 }
 
 impl Out {
-    pub(crate) fn new(root: &serde_json::Value) -> Arc<dyn crate::route::Out + Send + Sync> {
+    pub(crate) fn new(config: &OutConfig) -> Arc<dyn crate::route::Out + Send + Sync> {
         Arc::new(Self {
-            tag: root["tag"].as_str().expect("tag not found").to_string(),
-            tcp_nodelay: root["tcp_nodelay"].as_bool().unwrap_or_else(|| true),
-            tcp_keepalive_inverval: Duration::from_nanos(
-                (root["tcp_keepalive_inverval"]
-                    .as_f64()
-                    .unwrap_or_else(|| 30f64)
-                    * 1000_000_000f64) as u64,
-            ),
-            tcp_timeout: Duration::from_nanos(
-                (root["tcp_timeout"].as_f64().unwrap_or_else(|| 300f64) * 1000_000_000f64) as u64,
-            ),
-            udp_timeout: Duration::from_nanos(
-                (root["udp_timeout"].as_f64().unwrap_or_else(|| 60f64) * 1000_000_000f64) as u64,
-            ),
+            tag: config.tag.clone(),
+            tcp_nodelay: config.tcp_nodelay,
+            tcp_keepalive_interval: config.tcp_keepalive_interval,
+            tcp_timeout: config.tcp_timeout,
+            udp_timeout: config.udp_timeout,
         })
     }
 }
@@ -22,11 +22,11 @@ impl crate::route::OutTcp for super::Out {
         crate::misc::set_nodelay_keepalive_interval(
             &server,
             self.tcp_nodelay,
-            self.tcp_keepalive_inverval,
+            self.tcp_keepalive_interval,
         )?;
         let (mut server_rx, mut server_tx) = server.into_split();
 
-        tokio::spawn(async move {
+        crate::route::spawn_in_span(async move {
             let mut buf = vec![0; TCP_LEN];
             match bidirectional_with_timeout!(
                 {
@@ -56,6 +56,7 @@ impl crate::route::OutTcp for super::Out {
                 // client or timeout error
                 (Err(e), _, _) | (_, _, Err(e)) => {
                     let e = e.to_string();
+                    crate::route::report_out_close(&saddr, &e);
                     if e.as_str() == "close" {
                         debug!("{} {} -> {} {}", self.tag, saddr, daddr, e)
                     } else {
@@ -65,6 +66,7 @@ impl crate::route::OutTcp for super::Out {
                 // server error
                 (_, Err(e), _) => {
                     let e = e.to_string();
+                    crate::route::report_out_close(&saddr, &e);
                     if e.as_str() == "close" {
                         debug!("{} {} -> {} {}", self.tag, daddr, saddr, e)
                     } else {
@@ -13,10 +13,10 @@ impl crate::route::OutUdp for super::Out {
     ) -> Result<(), Box<dyn std::error::Error>> {
         // bind
         let server = Arc::new(tokio::net::UdpSocket::from_std(
-            crate::misc::build_socket_listener("udp", "[::]:0")?.into(),
+            crate::misc::build_socket_listener("udp", "[::]:0", None)?.into(),
         )?);
 
-        tokio::spawn(async move {
+        crate::route::spawn_in_span(async move {
             let mut buf = vec![0; UDP_LEN];
             match bidirectional_with_timeout!(
                 {
@@ -45,6 +45,7 @@ impl crate::route::OutUdp for super::Out {
             ) {
                 (Err(e), _, _) | (_, _, Err(e)) | (_, Err(e), _) => {
                     let e = e.to_string();
+                    crate::route::report_out_close(&saddr, &e);
                     if e.as_str() == "close" || e.as_str() == "timeout" {
                         debug!("{} {} {}", self.tag, saddr, e)
                     } else {
@@ -0,0 +1,144 @@
+// linux capability numbers, from include/uapi/linux/capability.h
+const CAPABILITIES: [&str; 41] = [
+    "chown",
+    "dac_override",
+    "dac_read_search",
+    "fowner",
+    "fsetid",
+    "kill",
+    "setgid",
+    "setuid",
+    "setpcap",
+    "linux_immutable",
+    "net_bind_service",
+    "net_broadcast",
+    "net_admin",
+    "net_raw",
+    "ipc_lock",
+    "ipc_owner",
+    "sys_module",
+    "sys_rawio",
+    "sys_chroot",
+    "sys_ptrace",
+    "sys_pacct",
+    "sys_admin",
+    "sys_boot",
+    "sys_nice",
+    "sys_resource",
+    "sys_time",
+    "sys_tty_config",
+    "mknod",
+    "lease",
+    "audit_write",
+    "audit_control",
+    "setfcap",
+    "mac_override",
+    "mac_admin",
+    "syslog",
+    "wake_alarm",
+    "block_suspend",
+    "audit_read",
+    "perfmon",
+    "bpf",
+    "checkpoint_restore",
+];
+
+// "net_admin" or "CAP_NET_ADMIN"
+pub(crate) fn parse_capability(name: &str) -> Option<u32> {
+    let name = name.to_ascii_lowercase();
+    let name = name.strip_prefix("cap_").unwrap_or(&name);
+
+    CAPABILITIES
+        .iter()
+        .position(|x| *x == name)
+        .map(|x| x as u32)
+}
+
+// without capabilities this is a plain setuid.
+// otherwise only the listed capabilities survive, effective for stn itself and
+// ambient for the commands it runs, so tproxy and ports below 1024 work as nobody
+#[cfg(not(target_os = "windows"))]
+pub(crate) fn setuid(uid: u32, capabilities: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Error;
+
+    #[repr(C)]
+    struct CapHeader {
+        version: u32,
+        pid: libc::c_int,
+    }
+
+    #[repr(C)]
+    #[derive(Default, Clone, Copy)]
+    struct CapData {
+        effective: u32,
+        permitted: u32,
+        inheritable: u32,
+    }
+
+    let mut caps = Vec::new();
+    for name in capabilities {
+        caps.push(parse_capability(name).ok_or_else(|| format!("unknown capability {}", name))?);
+    }
+
+    unsafe {
+        if caps.is_empty() {
+            if libc::setuid(uid as _) != 0 {
+                Err(format!("setuid {}: {}", uid, Error::last_os_error()))?
+            }
+            return Ok(());
+        }
+
+        // permitted capabilities are kept across setuid, effective ones are cleared
+        if libc::prctl(libc::PR_SET_KEEPCAPS, 1 as libc::c_ulong, 0, 0, 0) != 0 {
+            Err(format!("keep capabilities: {}", Error::last_os_error()))?
+        }
+        if libc::setuid(uid as _) != 0 {
+            Err(format!("setuid {}: {}", uid, Error::last_os_error()))?
+        }
+        libc::prctl(libc::PR_SET_KEEPCAPS, 0 as libc::c_ulong, 0, 0, 0);
+
+        // _LINUX_CAPABILITY_VERSION_3, two 32 bit words
+        let header = CapHeader {
+            version: 0x20080522,
+            pid: 0,
+        };
+        let mut data = [CapData::default(); 2];
+        for cap in &caps {
+            let bit = 1u32 << (cap % 32);
+            let word = &mut data[(cap / 32) as usize];
+            word.effective |= bit;
+            word.permitted |= bit;
+            word.inheritable |= bit;
+        }
+        if libc::syscall(libc::SYS_capset, &header, data.as_ptr()) != 0 {
+            Err(format!("capset: {}", Error::last_os_error()))?
+        }
+
+        for cap in &caps {
+            if libc::prctl(
+                libc::PR_CAP_AMBIENT,
+                libc::PR_CAP_AMBIENT_RAISE as libc::c_ulong,
+                *cap as libc::c_ulong,
+                0 as libc::c_ulong,
+                0 as libc::c_ulong,
+            ) != 0
+            {
+                Err(format!(
+                    "ambient capability {}: {}",
+                    CAPABILITIES[*cap as usize],
+                    Error::last_os_error()
+                ))?
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_capability() {
+    assert_eq!(parse_capability("net_admin"), Some(12));
+    assert_eq!(parse_capability("CAP_NET_BIND_SERVICE"), Some(10));
+    assert_eq!(parse_capability("checkpoint_restore"), Some(40));
+    assert_eq!(parse_capability("net_admins"), None);
+}
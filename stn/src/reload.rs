@@ -0,0 +1,44 @@
+use crate::{config, hook, route};
+use log::*;
+use serde_json::json;
+
+// only out and route are reloaded, in, setting and resolve need restart
+pub(crate) fn reload(
+    path: &str,
+    format: Option<config::Format>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let config = config::load(path, format)?;
+    route::route_out_parse(&config)?;
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+pub(crate) async fn reload_on_sighup(path: String, format: Option<config::Format>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(o) => o,
+        Err(e) => {
+            warn!("{}", e);
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        info!("reload {}", path);
+        match reload(&path, format) {
+            Ok(_) => {
+                info!("reload done");
+                hook::emit("reload", || json!({ "result": "ok" }));
+            }
+            Err(e) => {
+                error!("reload failed, keep the old configuration:\n{}", e);
+                hook::emit(
+                    "reload",
+                    || json!({ "result": "failed", "error": e.to_string() }),
+                );
+            }
+        }
+    }
+}
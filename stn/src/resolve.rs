@@ -1,9 +1,14 @@
 use crate::{
-    dns::get_server_and_refresh_system,
+    config::deserialize_secs,
+    dns::{
+        default_cache_size, default_max_ttl, default_min_ttl, default_refresh_system,
+        default_server, get_server_and_refresh_system,
+    },
     misc::{is_valid_domain, split_addr_str},
 };
 use log::*;
 use parking_lot::{Mutex, RwLock};
+use serde::Deserialize;
 use std::{
     net::{IpAddr, SocketAddr},
     str::FromStr,
@@ -38,33 +43,76 @@ struct Resolve {
     ipv6_first: bool,
 }
 
-pub(crate) fn init_resolve(root: &serde_json::Value) {
-    let server = get_server_and_refresh_system(&root);
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ResolveConfig {
+    #[serde(default = "default_tag")]
+    pub(crate) tag: String,
+    #[serde(default = "default_server")]
+    pub(crate) server: Vec<String>,
+    #[serde(
+        default = "default_refresh_system",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub(crate) refresh_system: Duration,
+    #[serde(default = "default_udp_timeout", deserialize_with = "deserialize_secs")]
+    pub(crate) udp_timeout: Duration,
+    #[serde(default = "default_cache_size")]
+    pub(crate) cache_size: usize,
+    #[serde(default)]
+    pub(crate) refresh_cache: bool,
+    #[serde(default = "default_min_ttl")]
+    pub(crate) min_ttl: u32,
+    #[serde(default = "default_max_ttl")]
+    pub(crate) max_ttl: u32,
+    #[serde(default)]
+    pub(crate) ipv6_first: bool,
+}
+
+impl Default for ResolveConfig {
+    fn default() -> Self {
+        Self {
+            tag: default_tag(),
+            server: default_server(),
+            refresh_system: default_refresh_system(),
+            udp_timeout: default_udp_timeout(),
+            cache_size: default_cache_size(),
+            refresh_cache: false,
+            min_ttl: default_min_ttl(),
+            max_ttl: default_max_ttl(),
+            ipv6_first: false,
+        }
+    }
+}
+
+fn default_tag() -> String {
+    "resolve".to_string()
+}
+
+fn default_udp_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+pub(crate) fn init_resolve(config: &ResolveConfig) {
+    let server = get_server_and_refresh_system(&config.tag, &config.server, config.refresh_system);
 
     let mut resolve_write = RESOLVE.write();
-    resolve_write.tag = root["tag"]
-        .as_str()
-        .unwrap_or_else(|| "resolve")
-        .to_string();
+    resolve_write.tag = config.tag.clone();
     resolve_write.server = server;
-    resolve_write.udp_timeout = Duration::from_nanos(
-        (root["udp_timeout"].as_f64().unwrap_or_else(|| 5f64) * 1000_000_000f64) as _,
-    );
-    resolve_write.min_ttl = root["min_ttl"].as_u64().unwrap_or_else(|| 60) as _;
-    resolve_write.max_ttl = root["max_ttl"].as_u64().unwrap_or_else(|| 2147483647) as _;
-    resolve_write.cache = Mutex::new(lru::LruCache::new(
-        root["cache_size"].as_u64().unwrap_or_else(|| 1024) as _,
-    ));
-    resolve_write.ipv6_first = root["ipv6_first"].as_bool().unwrap_or_else(|| false);
+    resolve_write.udp_timeout = config.udp_timeout;
+    resolve_write.min_ttl = config.min_ttl;
+    resolve_write.max_ttl = config.max_ttl;
+    resolve_write.cache = Mutex::new(lru::LruCache::new(config.cache_size));
+    resolve_write.ipv6_first = config.ipv6_first;
 
     // refresh_cache
-    if root["refresh_cache"].as_bool().unwrap_or_else(|| false) {
+    if config.refresh_cache {
         tokio::spawn(refresh_cache());
     }
 }
 
-pub(crate) async fn resolve(addr_str: &String) -> Result<String, Box<dyn std::error::Error>> {
-    let (domain, port) = split_addr_str(&addr_str)?;
+pub(crate) async fn resolve(addr_str: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let (domain, port) = split_addr_str(addr_str)?;
 
     // if ip addr
     if domain.parse::<IpAddr>().is_ok() {
@@ -84,10 +132,12 @@ pub(crate) async fn resolve(addr_str: &String) -> Result<String, Box<dyn std::er
             if tokio::time::Instant::now() > *deadline {
                 cache_lock.pop(&domain);
             } else {
+                crate::metrics::count_dns_cache(&resolve_read.tag, true);
                 debug!("{}:{} resolve to {}:{}", domain, port, answer, port);
                 return Ok(format!("{}:{}", answer, port));
             }
         }
+        crate::metrics::count_dns_cache(&resolve_read.tag, false);
     }
 
     // bind
@@ -118,9 +168,9 @@ pub(crate) async fn resolve(addr_str: &String) -> Result<String, Box<dyn std::er
             tasks.push(tokio::spawn({
                 let server_tx = server_tx.clone();
                 let buf = buf.clone();
-                let daddr = daddr.clone();
+                let daddr = *daddr;
                 async move {
-                    if let Err(_) = server_tx.send((daddr.to_string(), buf)).await {
+                    if server_tx.send((daddr.to_string(), buf)).await.is_err() {
                         debug!("channel close");
                     }
                 }
@@ -144,13 +194,13 @@ pub(crate) async fn resolve(addr_str: &String) -> Result<String, Box<dyn std::er
             };
 
             // wrong_first
-            if ((RESOLVE.read().ipv6_first == false && dns_msg.id() == 4)
+            if ((!RESOLVE.read().ipv6_first && dns_msg.id() == 4)
                 || (RESOLVE.read().ipv6_first && dns_msg.id() == 6))
                 && (dns_msg.response_code() != ResponseCode::NoError
-                    || !dns_msg.answers().iter().any(|x| match x.rdata() {
-                        RData::A(_) | RData::AAAA(_) => true,
-                        _ => false,
-                    }))
+                    || !dns_msg
+                        .answers()
+                        .iter()
+                        .any(|x| matches!(x.rdata(), RData::A(_) | RData::AAAA(_))))
             {
                 if let Some((answer, _)) = RESOLVE.read().cache.lock().get(&domain) {
                     return Ok(answer.clone());
@@ -167,7 +217,7 @@ pub(crate) async fn resolve(addr_str: &String) -> Result<String, Box<dyn std::er
                     .min(RESOLVE.read().max_ttl) as _;
 
                 let (addr, is_first) = match answer.rdata() {
-                    RData::A(addr) => (addr.to_string(), RESOLVE.read().ipv6_first == false),
+                    RData::A(addr) => (addr.to_string(), !RESOLVE.read().ipv6_first),
                     RData::AAAA(addr) => (addr.to_string(), RESOLVE.read().ipv6_first),
                     _ => continue,
                 };
@@ -203,6 +253,21 @@ pub(crate) async fn resolve(addr_str: &String) -> Result<String, Box<dyn std::er
     r
 }
 
+// ip of a daddr as resolve last answered, without a lookup
+pub(crate) fn cached(addr_str: &str) -> Option<String> {
+    let (domain, _) = split_addr_str(addr_str).ok()?;
+    if domain.parse::<IpAddr>().is_ok() {
+        return Some(domain);
+    }
+
+    RESOLVE
+        .read()
+        .cache
+        .lock()
+        .peek(&domain)
+        .map(|(answer, _)| answer.clone())
+}
+
 async fn refresh_cache() {
     let interval = if RESOLVE.read().min_ttl == 0 {
         return;
@@ -0,0 +1,332 @@
+use super::{Charge, Jump};
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::watch;
+use tracing::{error_span, field::Empty, Instrument, Span};
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+lazy_static::lazy_static! {
+    // live flows of tcp_connect and udp_bind
+    static ref FLOWS: DashMap<u64, Arc<Flow>> = DashMap::new();
+    // (tag, saddr) -> ids, for the in to report why it closed
+    static ref SOURCES: DashMap<(String, String), Vec<u64>> = DashMap::new();
+}
+
+// one tcp connection, or one udp association with one out
+pub(crate) struct Flow {
+    pub(crate) id: u64,
+    pub(crate) network: &'static str,
+    pub(crate) tag: String,
+    pub(crate) out: String,
+    pub(crate) saddr: String,
+    pub(crate) daddr: String,
+    // matched route index, None for the default out
+    pub(crate) route: Option<usize>,
+    // set by "log": true or setting.route_log
+    pub(crate) matched: Option<String>,
+    pub(crate) start: SystemTime,
+    pub(crate) up: AtomicU64,
+    pub(crate) down: AtomicU64,
+    // milliseconds since UNIX_EPOCH
+    last_active: AtomicU64,
+    kill_tx: Arc<watch::Sender<bool>>,
+    // "in", "out", "admin" or "quota", the first side that ended the relay
+    closed_by: Mutex<Option<&'static str>>,
+    // "close", "timeout", "killed" or the error
+    reason: Mutex<Option<String>>,
+    // (up, down) already given to accounting
+    accounted: Mutex<(u64, u64)>,
+    quota: Option<Charge>,
+}
+
+// what the admin api shows
+#[derive(Serialize)]
+pub(crate) struct FlowInfo {
+    id: u64,
+    network: &'static str,
+    tag: String,
+    out: String,
+    saddr: String,
+    daddr: String,
+    route: Option<usize>,
+    matched: Option<String>,
+    start: f64,
+    last_active: f64,
+    up: u64,
+    down: u64,
+}
+
+// unregistered when the last relay holding it ends
+pub(crate) struct Registered(pub(crate) Arc<Flow>);
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        let flow = &self.0;
+        FLOWS.remove(&flow.id);
+        let source = (flow.tag.clone(), flow.saddr.clone());
+        if let Some(mut ids) = SOURCES.get_mut(&source) {
+            ids.retain(|x| *x != flow.id);
+        }
+        SOURCES.remove_if(&source, |_, ids| ids.is_empty());
+
+        flow.account(0);
+
+        crate::access_log::write(|| flow.record());
+    }
+}
+
+impl Flow {
+    // flows sharing kill_tx are killed together, like the outs of one udp association
+    pub(crate) fn register(
+        id: u64,
+        network: &'static str,
+        tag: &str,
+        jump: &Jump,
+        saddr: &str,
+        daddr: &str,
+        kill_tx: Arc<watch::Sender<bool>>,
+    ) -> Arc<Registered> {
+        let flow = Arc::new(Flow {
+            id,
+            network,
+            tag: tag.to_string(),
+            out: jump.tag.clone(),
+            saddr: saddr.to_string(),
+            daddr: daddr.to_string(),
+            route: jump.index,
+            matched: jump.matched.clone(),
+            start: SystemTime::now(),
+            up: AtomicU64::new(0),
+            down: AtomicU64::new(0),
+            last_active: AtomicU64::new(unix_millis(SystemTime::now())),
+            kill_tx,
+            closed_by: Mutex::new(None),
+            reason: Mutex::new(None),
+            accounted: Mutex::new((0, 0)),
+            quota: jump.quota.clone(),
+        });
+        FLOWS.insert(flow.id, flow.clone());
+        SOURCES
+            .entry((flow.tag.clone(), flow.saddr.clone()))
+            .or_default()
+            .push(flow.id);
+        flow.account(1);
+
+        Arc::new(Registered(flow))
+    }
+
+    // false once its quota is exhausted
+    pub(crate) fn add(&self, up: bool, len: usize) -> bool {
+        let bytes = if up { &self.up } else { &self.down };
+        bytes.fetch_add(len as u64, Ordering::Relaxed);
+        self.last_active
+            .store(unix_millis(SystemTime::now()), Ordering::Relaxed);
+
+        // new flows take the fallback. a tcp flow is killed, a udp association closes
+        // only this flow and sends its next packets to the fallback
+        if let Some(quota) = &self.quota {
+            if !quota.add(len as u64) {
+                self.close("quota", Some("quota exceeded"));
+                if self.network == "tcp" {
+                    let _ = self.kill_tx.send(true);
+                }
+                return false;
+            }
+        }
+
+        true
+    }
+
+    pub(crate) fn kill_rx(&self) -> watch::Receiver<bool> {
+        self.kill_tx.subscribe()
+    }
+
+    // only the first call counts
+    pub(crate) fn close(&self, side: &'static str, reason: Option<&str>) {
+        self.closed_by.lock().get_or_insert(side);
+        if let Some(reason) = reason {
+            self.reason.lock().get_or_insert_with(|| reason.to_string());
+        }
+    }
+
+    // bytes since the last call
+    fn account(&self, flows: u64) {
+        let mut accounted = self.accounted.lock();
+        let up = self.up.load(Ordering::Relaxed);
+        let down = self.down.load(Ordering::Relaxed);
+        crate::accounting::add(
+            &self.tag,
+            &self.out,
+            &self.saddr,
+            up - accounted.0,
+            down - accounted.1,
+            flows,
+        );
+        *accounted = (up, down);
+    }
+
+    // access log
+    fn record(&self) -> serde_json::Value {
+        let end = SystemTime::now();
+        json!({
+            "time": unix_millis(end) as f64 / 1000f64,
+            "id": self.id,
+            "network": self.network,
+            "tag": self.tag,
+            "route": self.route,
+            "matched": self.matched,
+            "out": self.out,
+            "saddr": self.saddr,
+            "daddr": self.daddr,
+            "resolved": crate::resolve::cached(&self.daddr),
+            "duration": end.duration_since(self.start).unwrap_or_default().as_secs_f64(),
+            "up": self.up.load(Ordering::Relaxed),
+            "down": self.down.load(Ordering::Relaxed),
+            "closed_by": *self.closed_by.lock(),
+            "reason": self.reason.lock().as_deref().unwrap_or("close"),
+        })
+    }
+
+    fn info(&self) -> FlowInfo {
+        FlowInfo {
+            id: self.id,
+            network: self.network,
+            tag: self.tag.clone(),
+            out: self.out.clone(),
+            saddr: self.saddr.clone(),
+            daddr: self.daddr.clone(),
+            route: self.route,
+            matched: self.matched.clone(),
+            start: unix_millis(self.start) as f64 / 1000f64,
+            last_active: self.last_active.load(Ordering::Relaxed) as f64 / 1000f64,
+            up: self.up.load(Ordering::Relaxed),
+            down: self.down.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// the id of logs, the admin api and the access log
+pub(crate) fn next_flow_id() -> u64 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+// error level, so that every enabled event is inside it
+pub(crate) fn in_span(tag: &str, saddr: &dyn std::fmt::Display) -> Span {
+    error_span!("in", tag, saddr = %saddr, flow = Empty)
+}
+
+// tokio::spawn, keeping the span of the caller
+pub(crate) fn spawn_in_span<F>(future: F) -> tokio::task::JoinHandle<F::Output>
+where
+    F: std::future::Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(future.in_current_span())
+}
+
+// a child of the in, or of the flow of a chained out
+pub(crate) fn flow_span(id: u64, network: &str, tag: &str, saddr: &str, daddr: &str) -> Span {
+    // the in shows which flow it became, the span of a chained out has no "flow"
+    Span::current().record("flow", id);
+    error_span!("flow", id, network, tag, saddr, daddr, out = Empty)
+}
+
+// return when the flow is killed
+pub(crate) async fn wait_kill(mut kill_rx: watch::Receiver<bool>) {
+    while !*kill_rx.borrow() {
+        if kill_rx.changed().await.is_err() {
+            // never killed
+            futures::future::pending::<()>().await;
+        }
+    }
+}
+
+pub(crate) fn list_flows() -> Vec<FlowInfo> {
+    let mut flows: Vec<FlowInfo> = FLOWS.iter().map(|x| x.value().info()).collect();
+    flows.sort_by_key(|x| x.id);
+    flows
+}
+
+// false if not found
+pub(crate) fn kill_flow(id: u64) -> bool {
+    match FLOWS.get(&id) {
+        Some(flow) => {
+            let _ = flow.kill_tx.send(true);
+            true
+        }
+        None => false,
+    }
+}
+
+// count the bytes of live flows into the current hour
+pub(crate) fn account_flows() {
+    for flow in FLOWS.iter() {
+        flow.account(0);
+    }
+}
+
+// called by the in when its relay ends, before the flows are unregistered
+pub(crate) fn report_close(tag: &str, saddr: &str, reason: &str) {
+    let ids = match SOURCES.get(&(tag.to_string(), saddr.to_string())) {
+        Some(ids) => ids.clone(),
+        None => return,
+    };
+    for id in ids {
+        if let Some(flow) = FLOWS.get(&id) {
+            flow.close("in", Some(reason));
+        }
+    }
+}
+
+// called by an out when its relay ends, saddr is the "[tag]:[id]" the flow gave it.
+// "close" is left to the relays, which see the closed channel
+pub(crate) fn report_out_close(saddr: &str, reason: &str) {
+    if reason == "close" {
+        return;
+    }
+    let id = match saddr.rsplit(':').next().and_then(|x| x.parse::<u64>().ok()) {
+        Some(s) => s,
+        None => return,
+    };
+    if let Some(flow) = FLOWS.get(&id) {
+        flow.close("out", Some(reason));
+    }
+}
+
+// a tcp_connect that never became a flow
+pub(crate) fn log_connect_error(tag: &str, jump: &Jump, saddr: &str, daddr: &str, e: &str) {
+    crate::access_log::write(|| {
+        json!({
+            "time": unix_millis(SystemTime::now()) as f64 / 1000f64,
+            "network": "tcp",
+            "tag": tag,
+            "route": jump.index,
+            "matched": jump.matched,
+            "out": jump.tag,
+            "saddr": saddr,
+            "daddr": daddr,
+            "resolved": crate::resolve::cached(daddr),
+            "duration": 0,
+            "up": 0,
+            "down": 0,
+            "closed_by": "out",
+            "reason": e,
+        })
+    });
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|x| x.as_millis() as u64)
+        .unwrap_or_default()
+}
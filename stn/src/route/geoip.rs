@@ -0,0 +1,135 @@
+use lazy_static::lazy_static;
+use maxminddb::{geoip2, Reader};
+use parking_lot::RwLock;
+use std::{net::IpAddr, sync::Arc};
+
+lazy_static! {
+    // setting.geoip, opened again on every reload
+    pub(crate) static ref GEOIP: RwLock<Option<Arc<Reader<Vec<u8>>>>> = RwLock::new(None);
+}
+
+pub(crate) fn open_geoip(path: &str) -> Result<Option<Arc<Reader<Vec<u8>>>>, String> {
+    if path.is_empty() {
+        return Ok(None);
+    }
+
+    Reader::open_readfile(path)
+        .map(|x| Some(Arc::new(x)))
+        .map_err(|e| format!("setting.geoip: failed to open {}: {}", path, e))
+}
+
+// "geoip CN" or "geoip !private", a country code or private
+#[derive(Default)]
+pub(crate) struct GeoIpSet {
+    entries: Vec<(String, bool)>,
+}
+
+impl GeoIpSet {
+    pub(crate) fn insert(&mut self, value: &str) -> Result<(), String> {
+        let (negate, code) = match value.strip_prefix('!') {
+            Some(s) => (true, s),
+            None => (false, value),
+        };
+        let code = if code.eq_ignore_ascii_case("private") {
+            "private".to_string()
+        } else if code.len() == 2 && code.chars().all(|x| x.is_ascii_alphabetic()) {
+            code.to_ascii_uppercase()
+        } else {
+            Err(format!("invalid route geoip {}", value))?
+        };
+
+        self.entries.push((code, negate));
+        Ok(())
+    }
+
+    // any country code needs setting.geoip, private doesn't
+    pub(crate) fn needs_reader(&self) -> bool {
+        self.entries.iter().any(|(code, _)| code != "private")
+    }
+
+    // the country is looked up at most once
+    pub(crate) fn is_match(&self, ip: IpAddr) -> bool {
+        let mut country: Option<Option<String>> = None;
+
+        self.entries.iter().any(|(code, negate)| {
+            let hit = if code == "private" {
+                is_private(ip)
+            } else {
+                country.get_or_insert_with(|| lookup_country(ip)).as_deref() == Some(code.as_str())
+            };
+            hit != *negate
+        })
+    }
+}
+
+fn lookup_country(ip: IpAddr) -> Option<String> {
+    let reader = GEOIP.read().clone()?;
+    let country: geoip2::Country = reader.lookup(ip).ok()?;
+
+    country
+        .country
+        .or(country.registered_country)?
+        .iso_code
+        .map(|x| x.to_string())
+}
+
+// loopback, lan, link local, cgnat, multicast and other reserved ranges
+pub(crate) fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_multicast()
+                || ip.is_broadcast()
+                || octets[0] == 0
+                // 100.64.0.0/10
+                || (octets[0] == 100 && octets[1] & 0xc0 == 64)
+                // 198.18.0.0/15
+                || (octets[0] == 198 && octets[1] & 0xfe == 18)
+                // 240.0.0.0/4
+                || octets[0] >= 240
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_private(IpAddr::V4(ip));
+            }
+            let segments = ip.segments();
+            ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // fc00::/7
+                || segments[0] & 0xfe00 == 0xfc00
+                // fe80::/10
+                || segments[0] & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+#[test]
+fn test_geoip_private() {
+    let mut set = GeoIpSet::default();
+    set.insert("!private").unwrap();
+    assert!(set.insert("china").is_err());
+    assert!(!set.needs_reader());
+
+    for ip in [
+        "10.1.2.3",
+        "100.100.0.1",
+        "127.0.0.1",
+        "::ffff:192.168.1.1",
+        "fd00::1",
+    ]
+    .iter()
+    {
+        assert!(!set.is_match(ip.parse().unwrap()), "{}", ip);
+    }
+    for ip in ["8.8.8.8", "100.128.0.1", "2001:4860::8888"].iter() {
+        assert!(set.is_match(ip.parse().unwrap()), "{}", ip);
+    }
+
+    set.insert("cn").unwrap();
+    assert!(set.needs_reader());
+}
@@ -0,0 +1,248 @@
+use prost::Message;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+// setting.geosite, a v2ray geosite.dat or the data directory of domain-list-community
+pub(crate) enum GeoSite {
+    Dat(HashMap<String, Vec<SiteDomain>>),
+    Dir(PathBuf),
+}
+
+pub(crate) struct SiteDomain {
+    // kind of route addr, like "domain"
+    kind: &'static str,
+    value: String,
+    attrs: Vec<String>,
+}
+
+// v2ray-core app/router/config.proto, unused fields are skipped
+#[derive(Clone, PartialEq, Message)]
+struct GeoSiteList {
+    #[prost(message, repeated, tag = "1")]
+    entry: Vec<GeoSiteEntry>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct GeoSiteEntry {
+    #[prost(string, tag = "1")]
+    country_code: String,
+    #[prost(message, repeated, tag = "2")]
+    domain: Vec<DatDomain>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct DatDomain {
+    // Plain, Regex, Domain, Full
+    #[prost(int32, tag = "1")]
+    r#type: i32,
+    #[prost(string, tag = "2")]
+    value: String,
+    #[prost(message, repeated, tag = "3")]
+    attribute: Vec<DatAttribute>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct DatAttribute {
+    #[prost(string, tag = "1")]
+    key: String,
+}
+
+pub(crate) fn open_geosite(path: &str) -> Result<Option<GeoSite>, String> {
+    if path.is_empty() {
+        return Ok(None);
+    }
+
+    let error = |e: &dyn std::fmt::Display| format!("setting.geosite: {} {}", path, e);
+    if Path::new(path).is_dir() {
+        return Ok(Some(GeoSite::Dir(PathBuf::from(path))));
+    }
+
+    let buf = std::fs::read(path).map_err(|e| error(&e))?;
+    let list = GeoSiteList::decode(buf.as_slice()).map_err(|e| error(&e))?;
+    let mut sites = HashMap::new();
+    for entry in list.entry {
+        let domains = entry
+            .domain
+            .into_iter()
+            .filter_map(|x| {
+                let kind = match x.r#type {
+                    0 => "substring",
+                    1 => "regex",
+                    2 => "domain",
+                    3 => "full",
+                    _ => return None,
+                };
+                Some(SiteDomain {
+                    kind,
+                    value: x.value,
+                    attrs: x.attribute.into_iter().map(|x| x.key).collect(),
+                })
+            })
+            .collect();
+        sites.insert(entry.country_code.to_lowercase(), domains);
+    }
+
+    Ok(Some(GeoSite::Dat(sites)))
+}
+
+impl GeoSite {
+    // "google" or "category-ads-all@ads" -> ["domain google.com", ...]
+    pub(crate) fn expand(&self, value: &str) -> Result<Vec<String>, String> {
+        let mut split = value.split('@');
+        let name = split.next().unwrap_or_default().to_lowercase();
+        let filters: Vec<&str> = split.collect();
+
+        let domains = match self {
+            GeoSite::Dat(sites) => {
+                let domains = sites
+                    .get(&name)
+                    .ok_or_else(|| format!("geosite {} not found", name))?;
+                domains
+                    .iter()
+                    .filter(|x| has_attrs(&x.attrs, &filters))
+                    .map(|x| format!("{} {}", x.kind, x.value))
+                    .collect()
+            }
+            GeoSite::Dir(dir) => read_list(dir, &name, &mut HashSet::new())?
+                .into_iter()
+                .filter(|x| has_attrs(&x.attrs, &filters))
+                .map(|x| format!("{} {}", x.kind, x.value))
+                .collect(),
+        };
+
+        Ok(domains)
+    }
+}
+
+// "ads" needs the attribute, "!ads" needs its absence
+fn has_attrs(attrs: &[String], filters: &[&str]) -> bool {
+    filters.iter().all(|filter| match filter.strip_prefix('!') {
+        Some(filter) => !attrs.iter().any(|x| x == filter),
+        None => attrs.iter().any(|x| x == filter),
+    })
+}
+
+// "domain:a.com @ads", "full:", "regexp:", "keyword:" or "include:other @ads"
+fn read_list(
+    dir: &Path,
+    name: &str,
+    including: &mut HashSet<String>,
+) -> Result<Vec<SiteDomain>, String> {
+    // a list including itself
+    if !including.insert(name.to_string()) {
+        return Ok(Vec::new());
+    }
+
+    let path = dir.join(name);
+    let text = std::fs::read_to_string(&path)
+        .map_err(|e| format!("geosite {} {}: {}", name, path.display(), e))?;
+
+    let mut domains = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut split = line.split_whitespace();
+        let rule = match split.next() {
+            Some(s) => s,
+            None => continue,
+        };
+        let attrs: Vec<String> = split
+            .filter_map(|x| x.strip_prefix('@'))
+            .map(|x| x.to_string())
+            .collect();
+
+        let (kind, value) = match rule.find(':') {
+            Some(i) => (&rule[..i], &rule[i + 1..]),
+            None => ("domain", rule),
+        };
+        let kind = match kind {
+            "domain" => "domain",
+            "full" => "full",
+            "regexp" => "regex",
+            "keyword" => "substring",
+            "include" => {
+                // attributes of an include filter the included list
+                let filters: Vec<&str> = attrs.iter().map(|x| x.as_str()).collect();
+                domains.extend(
+                    read_list(dir, value, including)?
+                        .into_iter()
+                        .filter(|x| has_attrs(&x.attrs, &filters)),
+                );
+                continue;
+            }
+            kind => Err(format!(
+                "geosite {} line {}: {} not support",
+                name,
+                index + 1,
+                kind
+            ))?,
+        };
+        domains.push(SiteDomain {
+            kind,
+            value: value.to_string(),
+            attrs,
+        });
+    }
+    including.remove(name);
+
+    Ok(domains)
+}
+
+#[test]
+fn test_geosite() {
+    let dir = crate::misc::TestDir::new("geosite");
+    dir.write(
+        "list/ads",
+        "# comment\nads.com @ads\nfull:track.a.com @ads # inline\nkeyword:banner\n",
+    );
+    dir.write(
+        "list/all",
+        "include:ads @ads\nregexp:^x\\d+\\.com$\ninclude:all\ninclude:ads @!ads\n",
+    );
+
+    let geosite = open_geosite(dir.0.join("list").to_str().unwrap())
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        geosite.expand("all").unwrap(),
+        vec![
+            "domain ads.com",
+            "full track.a.com",
+            "regex ^x\\d+\\.com$",
+            "substring banner"
+        ]
+    );
+    assert_eq!(
+        geosite.expand("ads@!ads").unwrap(),
+        vec!["substring banner"]
+    );
+    assert!(geosite.expand("none").is_err());
+
+    let list = GeoSiteList {
+        entry: vec![GeoSiteEntry {
+            country_code: "GOOGLE".to_string(),
+            domain: vec![
+                DatDomain {
+                    r#type: 2,
+                    value: "google.com".to_string(),
+                    attribute: Vec::new(),
+                },
+                DatDomain {
+                    r#type: 3,
+                    value: "ads.google.com".to_string(),
+                    attribute: vec![DatAttribute {
+                        key: "ads".to_string(),
+                    }],
+                },
+            ],
+        }],
+    };
+    let path = dir.write("geosite.dat", list.encode_to_vec());
+    let geosite = open_geosite(&path).unwrap().unwrap();
+    assert_eq!(
+        geosite.expand("google@ads").unwrap(),
+        vec!["full ads.google.com"]
+    );
+    assert_eq!(geosite.expand("Google").unwrap().len(), 2);
+}
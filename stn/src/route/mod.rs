@@ -1,10 +1,26 @@
 #[macro_use]
 mod network;
+mod flow;
+mod geoip;
+mod geosite;
 mod out;
 mod parse;
+mod process;
+mod quic;
+mod quota;
 mod route;
+mod sniff;
+mod trace;
 
+pub(crate) use self::flow::*;
+pub(crate) use self::geoip::*;
+pub(crate) use self::geosite::*;
 pub(crate) use self::network::*;
 pub(crate) use self::out::*;
 pub(crate) use self::parse::*;
+pub(crate) use self::process::*;
+pub(crate) use self::quic::*;
+pub(crate) use self::quota::*;
 pub(crate) use self::route::*;
+pub(crate) use self::sniff::*;
+pub(crate) use self::trace::*;
@@ -1,33 +1,182 @@
-use crate::route::find_out;
+use crate::{
+    hook, metrics,
+    route::{
+        find_out_resolved, flow_span, is_quic_initial, log_connect_error, next_flow_id, read_first,
+        sniff_config, spawn_in_span, wait_kill, Flow, Jump, QuicPending, Registered, RouteCache,
+        Sniff,
+    },
+};
 use log::*;
-use std::collections::HashMap;
-use tokio::sync::mpsc::{channel, Receiver, Sender};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        watch,
+    },
+    time::{sleep_until, Instant},
+};
+use tracing::{Instrument, Span};
 
-// route global entry
+// (daddr, payload) of a udp packet
+type Packet = (String, Vec<u8>);
+// an out of a udp association, with its up bytes counter
+type UdpFlow = (Sender<Packet>, Arc<AtomicU64>, Arc<Registered>);
+
+// daddrs of a udp association holding quic Initial packets at once
+const QUIC_PENDING_MAX: usize = 16;
+// sniffed quic connections of a udp association, kept while they get packets
+const ROUTED_MAX: usize = 1024;
+const ROUTED_IDLE: Duration = Duration::from_secs(60);
+
+// route global entry, returns once the out is connected, so the in can reply to its client
 #[inline]
 pub(crate) async fn tcp_connect(
     tag: String,
     saddr: String,
     daddr: String,
+) -> Result<(Sender<Vec<u8>>, Receiver<Vec<u8>>), Box<dyn std::error::Error>> {
+    let id = next_flow_id();
+    let span = flow_span(id, "tcp", &tag, &saddr, &daddr);
+    connect(id, tag, saddr, daddr, false).instrument(span).await
+}
+
+// route global entry of an in which has nothing to reply, like tproxy and origin.
+// while sniffing, it returns before the out is chosen and a failed connect closes the channels
+#[inline]
+pub(crate) async fn tcp_connect_early(
+    tag: String,
+    saddr: String,
+    daddr: String,
+) -> Result<(Sender<Vec<u8>>, Receiver<Vec<u8>>), Box<dyn std::error::Error>> {
+    let id = next_flow_id();
+    let span = flow_span(id, "tcp", &tag, &saddr, &daddr);
+    connect(id, tag, saddr, daddr, true).instrument(span).await
+}
+
+async fn connect(
+    id: u64,
+    tag: String,
+    saddr: String,
+    daddr: String,
+    early: bool,
 ) -> Result<(Sender<Vec<u8>>, Receiver<Vec<u8>>), Box<dyn std::error::Error>> {
     debug!("{} {} -> {} connect", tag, saddr, daddr);
 
-    let (client_tx, server_rx) = channel(1);
-    let (server_tx, client_rx) = channel(1);
+    // in <-> relay <-> out, the relay counts bytes and can kill the flow
+    let (client_tx, relay_down_rx) = channel(1);
+    let (relay_up_tx, client_rx) = channel(1);
+    let (server_tx, mut relay_up_rx) = channel(1);
+    let (relay_down_tx, server_rx) = channel(1);
 
-    // tcp needn't dispatch
-    find_out(tag.clone(), "tcp".to_string(), saddr, daddr.clone(), &[])
-        .tcp_connect(
-            format!(
-                "{}:{}",
-                tag,
-                tag.as_bytes() as *const _ as *const usize as usize
-            ),
-            daddr,
-            client_tx,
-            client_rx,
+    // the client has to speak first, so only an in without a reply can be sniffed
+    let sniff = if early {
+        sniff_config(&tag, &daddr)
+    } else {
+        None
+    };
+    let sniffing = sniff.is_some();
+    let source = format!("{} {} -> {}", tag, saddr, daddr);
+    let route = async move {
+        // tcp needn't dispatch, but may be routed by its first bytes
+        let (daddr, buf, sniffed) = match sniff {
+            Some(sniff) => {
+                let buf = read_first(&mut relay_up_rx, &sniff)
+                    .await
+                    .ok_or("close before sniffed")?;
+                let sniffed = crate::route::sniff("tcp", &buf);
+                let daddr = match (sniff.override_daddr, &sniffed) {
+                    (true, Some(sniffed)) => {
+                        let dport = daddr.rsplit(':').next().unwrap_or_default();
+                        debug!("{} {} -> {} sniffed {}", tag, saddr, daddr, sniffed.domain);
+                        format!("{}:{}", sniffed.domain, dport)
+                    }
+                    _ => daddr,
+                };
+                (daddr, buf, sniffed)
+            }
+            None => (daddr, Vec::new(), None),
+        };
+
+        let jump = find_out_resolved(
+            tag.clone(),
+            "tcp".to_string(),
+            saddr.clone(),
+            daddr.clone(),
+            &buf,
+            sniffed.as_ref(),
+            &mut RouteCache::default(),
         )
-        .await?;
+        .await;
+        Span::current().record("out", jump.tag.as_str());
+        jump.log(&tag, "tcp", &saddr, &daddr);
+        // "[tag]:[id]" leads the logs of a chained out back to this flow
+        let result = jump
+            .out
+            .clone()
+            .tcp_connect(
+                format!("{}:{}", tag, id),
+                daddr.clone(),
+                client_tx,
+                client_rx,
+            )
+            .await
+            .map_err(|e| e.to_string());
+        count_connect(&jump.tag, "tcp", result.is_ok());
+        if let Err(e) = result {
+            log_connect_error(&tag, &jump, &saddr, &daddr, &e);
+            hook::connect_failed(&jump.tag, "tcp", &e);
+            return Err(e);
+        }
+
+        let (kill_tx, _) = watch::channel(false);
+        let flow = Flow::register(id, "tcp", &tag, &jump, &saddr, &daddr, Arc::new(kill_tx));
+        let up_counter = bytes_counter(&tag, &jump.tag, "up");
+        // bytes read by sniffing go first
+        if !buf.is_empty() {
+            up_counter.fetch_add(buf.len() as u64, Ordering::Relaxed);
+            flow.0.add(true, buf.len());
+            if relay_up_tx.send(buf).await.is_err() {
+                flow.0.close("out", None);
+                return Ok(());
+            }
+        }
+        spawn_in_span(relay(
+            relay_up_rx,
+            relay_up_tx,
+            |x| x.len(),
+            flow.clone(),
+            true,
+            up_counter,
+        ));
+        spawn_in_span(relay(
+            relay_down_rx,
+            relay_down_tx,
+            |x| x.len(),
+            flow,
+            false,
+            bytes_counter(&tag, &jump.tag, "down"),
+        ));
+
+        Ok(())
+    };
+
+    // the in gets its channels before the out is chosen, and learns a failed connect by their close
+    if sniffing {
+        spawn_in_span(async move {
+            if let Err(e) = route.await {
+                warn!("{} {}", source, e);
+            }
+        });
+    } else {
+        route.await?;
+    }
 
     Ok((server_tx, server_rx))
 }
@@ -37,65 +186,262 @@ pub(crate) async fn tcp_connect(
 pub(crate) fn udp_bind(
     tag: String,
     saddr: String,
-) -> Result<(Sender<(String, Vec<u8>)>, Receiver<(String, Vec<u8>)>), Box<dyn std::error::Error>> {
+) -> Result<(Sender<Packet>, Receiver<Packet>), Box<dyn std::error::Error>> {
     let (client_tx, server_rx) = channel::<(String, Vec<u8>)>(100);
     let (server_tx, mut client_rx) = channel::<(String, Vec<u8>)>(100);
 
     // dispatch, single src may have multi dst out
     tokio::spawn({
         async move {
-            let mut fullcone_map: HashMap<usize, Sender<(String, Vec<u8>)>> = HashMap::new();
-            let unique_port = Box::new(0u8).as_ref() as *const _ as usize;
+            let _association = metrics::GaugeGuard::new("stn_udp_associations", &[("tag", &tag)]);
+            // (out, quota usage) -> flow, a quota gets a flow of its own
+            let mut fullcone_map: HashMap<(usize, usize), UdpFlow> = HashMap::new();
+
+            // killing any flow of the association closes all of them
+            let (kill_tx, kill_rx) = watch::channel(false);
+            let kill_tx = Arc::new(kill_tx);
+
+            // quic Initial packets held until their ClientHello is sniffed, or sniff.timeout
+            let mut quic_pending: HashMap<String, QuicPending> = HashMap::new();
+            // sniffed quic connections, their later packets can't be sniffed.
+            // daddr -> (jump, last packet)
+            let mut jumps: HashMap<String, (Arc<Jump>, Instant)> = HashMap::new();
+            // every other packet is routed again, resolves and quotas are looked up once
+            let mut cache = RouteCache::default();
 
             // if None recv, return
-            while let Some((daddr, recv_data)) = client_rx.recv().await {
-                // out_usize as map key
-                let out = find_out(
-                    tag.clone(),
-                    "udp".to_string(),
-                    saddr.clone(),
-                    daddr.clone(),
-                    &recv_data,
+            loop {
+                let deadline = quic_pending.values().map(|x| x.deadline).min();
+                let (daddr, recv_data) = tokio::select! {
+                    r = client_rx.recv() => match r {
+                        Some((daddr, recv_data)) => (daddr, Some(recv_data)),
+                        None => {
+                            for (_, _, flow) in fullcone_map.values() {
+                                flow.0.close("in", None);
+                            }
+                            return;
+                        }
+                    },
+                    _ = wait_kill(kill_rx.clone()) => {
+                        for (_, _, flow) in fullcone_map.values() {
+                            flow.0.close("admin", Some("killed"));
+                        }
+                        return;
+                    }
+                    // the rest of a ClientHello never came
+                    _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                        match quic_pending.iter().min_by_key(|x| x.1.deadline) {
+                            Some((daddr, _)) => (daddr.clone(), None),
+                            None => continue,
+                        }
+                    }
+                };
+
+                let routed = match jumps.get_mut(&daddr) {
+                    // an exhausted quota routes it again, to the fallback
+                    Some(x)
+                        if x.1.elapsed() < ROUTED_IDLE
+                            && x.0.quota.as_ref().is_none_or(|x| x.add(0)) =>
+                    {
+                        x.1 = Instant::now();
+                        Some(x.0.clone())
+                    }
+                    _ => None,
+                };
+
+                // sniffed quic connections keep their out
+                let mut sticky = false;
+                let mut sniffed = None;
+                let packets = match recv_data {
+                    Some(data) if routed.is_none() && is_quic_initial(&data) => {
+                        match sniff_config(&tag, &daddr) {
+                            Some(config)
+                                if quic_pending.contains_key(&daddr)
+                                    || quic_pending.len() < QUIC_PENDING_MAX =>
+                            {
+                                let pending = quic_pending
+                                    .entry(daddr.clone())
+                                    .or_insert_with(|| QuicPending::new(Instant::now() + config.timeout));
+                                match pending.push(data) {
+                                    Sniff::Incomplete if pending.len < config.bytes => continue,
+                                    Sniff::Found(x) => sniffed = Some(x),
+                                    _ => {}
+                                }
+                                sticky = true;
+                                quic_pending.remove(&daddr).map(|x| x.datagrams)
+                            }
+                            _ => Some(vec![data]),
+                        }
+                    }
+                    Some(data) => Some(vec![data]),
+                    None => {
+                        sticky = true;
+                        quic_pending.remove(&daddr).map(|x| x.datagrams)
+                    }
+                };
+                let packets = match packets {
+                    Some(s) if !s.is_empty() => s,
+                    _ => continue,
+                };
+
+                let jump = match routed {
+                    Some(jump) => jump,
+                    None => {
+                        let jump = Arc::new(
+                            find_out_resolved(
+                                tag.clone(),
+                                "udp".to_string(),
+                                saddr.clone(),
+                                daddr.clone(),
+                                &packets[0],
+                                sniffed.as_ref(),
+                                &mut cache,
+                            )
+                            .await,
+                        );
+                        if sticky {
+                            if jumps.len() >= ROUTED_MAX {
+                                jumps.retain(|_, x| x.1.elapsed() < ROUTED_IDLE);
+                            }
+                            // still full, forget the least recent
+                            if jumps.len() >= ROUTED_MAX {
+                                if let Some(oldest) =
+                                    jumps.iter().min_by_key(|x| (x.1).1).map(|x| x.0.clone())
+                                {
+                                    jumps.remove(&oldest);
+                                }
+                            }
+                            jumps.insert(daddr.clone(), (jump.clone(), Instant::now()));
+                        }
+                        jump
+                    }
+                };
+                let key = (
+                    jump.out.as_ref() as *const _ as *const usize as usize,
+                    jump.quota.as_ref().map_or(0, |x| x.id()),
                 );
-                let out_usize = out.as_ref() as *const _ as *const usize as usize;
 
                 // get server_tx or new a task
-                let server_tx = if let Some(s) = fullcone_map.get(&out_usize) {
+                let (server_tx, up_counter, flow) = if let Some(s) = fullcone_map.get(&key) {
                     s.clone()
                 } else {
+                    // flows closed by their out or quota
+                    fullcone_map.retain(|_, x| !x.0.is_closed());
+
+                    let id = next_flow_id();
+                    let span = flow_span(id, "udp", &tag, &saddr, &daddr);
+                    span.record("out", jump.tag.as_str());
+                    span.in_scope(|| jump.log(&tag, "udp", &saddr, &daddr));
                     let (server_tx, server_rx) = channel(100);
-                    match out
-                        .udp_bind(
-                            format!("{}:{}", tag, unique_port),
+                    let (relay_down_tx, relay_down_rx) = channel(100);
+                    let result = jump
+                        .out
+                        .clone()
+                        .udp_bind(format!("{}:{}", tag, id), relay_down_tx, server_rx)
+                        .instrument(span.clone())
+                        .await;
+                    count_connect(&jump.tag, "udp", result.is_ok());
+                    if let Err(e) = result {
+                        span.in_scope(|| warn!("{} {} -> {} {}", tag, saddr, daddr, e));
+                        hook::connect_failed(&jump.tag, "udp", &e.to_string());
+                        continue;
+                    }
+
+                    let flow =
+                        Flow::register(id, "udp", &tag, &jump, &saddr, &daddr, kill_tx.clone());
+                    tokio::spawn(
+                        relay(
+                            relay_down_rx,
                             client_tx.clone(),
-                            server_rx,
+                            |x| x.1.len(),
+                            flow.clone(),
+                            false,
+                            bytes_counter(&tag, &jump.tag, "down"),
                         )
-                        .await
-                    {
-                        Ok(o) => o,
-                        Err(e) => {
-                            warn!("{} {} -> {} {}", tag, saddr, daddr, e);
-                            continue;
-                        }
-                    };
-                    fullcone_map.insert(out_usize, server_tx.clone());
-                    server_tx
+                        .instrument(span),
+                    );
+
+                    let up_counter = bytes_counter(&tag, &jump.tag, "up");
+                    fullcone_map.insert(key, (server_tx.clone(), up_counter.clone(), flow.clone()));
+                    (server_tx, up_counter, flow)
                 };
 
-                // send
-                if let Err(e) = server_tx.try_send((daddr.clone(), recv_data)) {
-                    warn!("{} {} -> {} {}", tag, saddr, daddr, e);
-                    continue;
+                // send, held packets in order
+                for data in packets {
+                    let len = data.len();
+                    if let Err(e) = server_tx.try_send((daddr.clone(), data)) {
+                        warn!("{} {} -> {} {}", tag, saddr, daddr, e);
+                        break;
+                    }
+                    up_counter.fetch_add(len as u64, Ordering::Relaxed);
+                    if !flow.0.add(true, len) {
+                        fullcone_map.remove(&key);
+                        break;
+                    }
                 }
             }
         }
+        .in_current_span()
     });
 
     Ok((server_tx, server_rx))
 }
 
+fn count_connect(out_tag: &str, network: &str, ok: bool) {
+    metrics::inc(
+        "stn_out_connect_total",
+        &[
+            ("out", out_tag),
+            ("network", network),
+            ("result", if ok { "ok" } else { "error" }),
+        ],
+    );
+}
+
+fn bytes_counter(tag: &str, out_tag: &str, direction: &str) -> Arc<AtomicU64> {
+    metrics::counter(
+        "stn_bytes_total",
+        &[("tag", tag), ("out", out_tag), ("direction", direction)],
+    )
+}
+
+// forward until either side closes or the flow is killed
+async fn relay<T>(
+    mut rx: Receiver<T>,
+    tx: Sender<T>,
+    len: fn(&T) -> usize,
+    flow: Arc<Registered>,
+    up: bool,
+    counter: Arc<AtomicU64>,
+) {
+    let kill_rx = flow.0.kill_rx();
+    let (rx_side, tx_side) = if up { ("in", "out") } else { ("out", "in") };
+
+    loop {
+        let data = tokio::select! {
+            r = rx.recv() => match r {
+                Some(s) => s,
+                None => return flow.0.close(rx_side, None),
+            },
+            _ = tx.closed() => return flow.0.close(tx_side, None),
+            _ = wait_kill(kill_rx.clone()) => return flow.0.close("admin", Some("killed")),
+        };
+        let n = len(&data);
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+        // over its quota, a udp association sends the next packets to the fallback
+        if !flow.0.add(up, n) {
+            return;
+        }
+        if tx.send(data).await.is_err() {
+            return flow.0.close(tx_side, None);
+        }
+    }
+}
+
 macro_rules! bidirectional_with_timeout {
     ($client_block:block, $server_block:block, $timeout:expr) => {{
+        // counted for graceful shutdown
+        let _relay_guard = crate::shutdown::RelayGuard::new();
         let (timer_tx, mut timer_rx) = tokio::sync::mpsc::channel::<()>(1);
         tokio::select! {
             r = async {
@@ -135,3 +481,40 @@ macro_rules! bidirectional_with_timeout {
         }
     }};
 }
+
+#[tokio::test]
+async fn test_sniffed_connect_failed() {
+    use crate::{origin, route::OUT};
+
+    // nothing listens on it
+    let daddr = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    };
+    let config: origin::OutConfig = serde_json::from_str(r#"{"tag": "origin"}"#).unwrap();
+    *OUT.write() = vec![("origin".to_string(), origin::Out::new(&config))];
+    crate::route::init_sniff(&Some(
+        serde_json::from_str(r#"{"tag": ["tp", "s5"]}"#).unwrap(),
+    ));
+    let hello = crate::route::build_tls_record(&crate::route::build_client_hello("a.com", &[]));
+
+    // tproxy gets its channels at once, and sees them closed
+    let (server_tx, mut server_rx) = tcp_connect_early(
+        "tp".to_string(),
+        "127.0.0.1:5000".to_string(),
+        daddr.clone(),
+    )
+    .await
+    .unwrap();
+    server_tx.send(hello).await.unwrap();
+    assert!(server_rx.recv().await.is_none());
+
+    // socks5 waits for the out before its reply, so it is not sniffed
+    assert!(
+        tcp_connect("s5".to_string(), "127.0.0.1:5001".to_string(), daddr)
+            .await
+            .is_err()
+    );
+
+    crate::route::init_sniff(&None);
+}
@@ -1,33 +1,136 @@
-use super::{Out, OUT, ROUTE};
-use crate::*;
-use log::*;
+use super::{
+    open_geoip, open_geosite, GeoIpSet, GeoSite, Out, Quota, QuotaConfig, GEOIP, OUT, ROUTE, TRACE,
+};
+use crate::{config::*, *};
 use serde::Deserialize;
 use std::{
     collections::HashMap,
     io::BufRead,
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
 };
 use treebitmap::IpLookupTable;
 
 #[derive(Debug, Clone, Deserialize)]
-struct RouteRaw {
+#[serde(deny_unknown_fields)]
+pub(crate) struct RouteConfig {
+    #[serde(default)]
+    pub(crate) tag: Vec<String>,
+    #[serde(default)]
+    pub(crate) network: Vec<String>,
+    #[serde(default)]
+    pub(crate) saddr: Vec<String>,
+    #[serde(default)]
+    pub(crate) sport: Vec<usize>,
+    #[serde(default)]
+    pub(crate) daddr: Vec<String>,
+    #[serde(default)]
+    pub(crate) dport: Vec<usize>,
+    #[serde(default)]
+    pub(crate) dns_domain: Vec<String>,
+    // owner of a local source, linux only
+    #[serde(default)]
+    pub(crate) process_name: Vec<String>,
     #[serde(default)]
-    tag: Vec<String>,
+    pub(crate) process_path: Vec<String>,
     #[serde(default)]
-    network: Vec<String>,
+    pub(crate) uid: Vec<u32>,
     #[serde(default)]
-    saddr: Vec<String>,
+    pub(crate) gid: Vec<u32>,
+    // first bytes of a tcp flow, with setting.sniff
     #[serde(default)]
-    sport: Vec<usize>,
+    pub(crate) sniff_domain: Vec<String>,
     #[serde(default)]
-    daddr: Vec<String>,
+    pub(crate) alpn: Vec<String>,
+    // sub-rules, matched along with the fields above
+    pub(crate) not: Option<Box<RuleConfig>>,
     #[serde(default)]
-    dport: Vec<usize>,
+    pub(crate) any: Vec<RuleConfig>,
     #[serde(default)]
-    dns_domain: Vec<String>,
+    pub(crate) all: Vec<RuleConfig>,
 
-    jump: String,
+    pub(crate) jump: String,
+    // log why a flow matched
+    #[serde(default)]
+    pub(crate) log: bool,
+    pub(crate) quota: Option<QuotaConfig>,
+    // setting.resolve_daddr by default
+    pub(crate) resolve_daddr: Option<ResolveDaddr>,
+}
+
+// the fields of a route without jump, nested in "not", "any" and "all"
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RuleConfig {
+    #[serde(default)]
+    pub(crate) tag: Vec<String>,
+    #[serde(default)]
+    pub(crate) network: Vec<String>,
+    #[serde(default)]
+    pub(crate) saddr: Vec<String>,
+    #[serde(default)]
+    pub(crate) sport: Vec<usize>,
+    #[serde(default)]
+    pub(crate) daddr: Vec<String>,
+    #[serde(default)]
+    pub(crate) dport: Vec<usize>,
+    #[serde(default)]
+    pub(crate) dns_domain: Vec<String>,
+    // owner of a local source, linux only
+    #[serde(default)]
+    pub(crate) process_name: Vec<String>,
+    #[serde(default)]
+    pub(crate) process_path: Vec<String>,
+    #[serde(default)]
+    pub(crate) uid: Vec<u32>,
+    #[serde(default)]
+    pub(crate) gid: Vec<u32>,
+    // first bytes of a tcp flow, with setting.sniff
+    #[serde(default)]
+    pub(crate) sniff_domain: Vec<String>,
+    #[serde(default)]
+    pub(crate) alpn: Vec<String>,
+    // sub-rules, matched along with the fields above
+    pub(crate) not: Option<Box<RuleConfig>>,
+    #[serde(default)]
+    pub(crate) any: Vec<RuleConfig>,
+    #[serde(default)]
+    pub(crate) all: Vec<RuleConfig>,
+}
+
+impl RouteConfig {
+    pub(crate) fn rule(&self) -> RuleConfig {
+        RuleConfig {
+            tag: self.tag.clone(),
+            network: self.network.clone(),
+            saddr: self.saddr.clone(),
+            sport: self.sport.clone(),
+            daddr: self.daddr.clone(),
+            dport: self.dport.clone(),
+            dns_domain: self.dns_domain.clone(),
+            process_name: self.process_name.clone(),
+            process_path: self.process_path.clone(),
+            uid: self.uid.clone(),
+            gid: self.gid.clone(),
+            sniff_domain: self.sniff_domain.clone(),
+            alpn: self.alpn.clone(),
+            not: self.not.clone(),
+            any: self.any.clone(),
+            all: self.all.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ResolveDaddr {
+    #[default]
+    Never,
+    // route the ip of a domain daddr again when no route matched the domain
+    IfNoDomainMatch,
 }
 
 pub(crate) struct RouteAddr {
@@ -37,10 +140,24 @@ pub(crate) struct RouteAddr {
     pub(crate) cidr4: IpLookupTable<Ipv4Addr, ()>,
     pub(crate) cidr6: IpLookupTable<Ipv6Addr, ()>,
     pub(crate) regex: regex::RegexSet,
+    pub(crate) geoip: GeoIpSet,
+    // no entry without "!"
     pub(crate) empty: bool,
+    // "!" entries, whatever they match is excluded
+    pub(crate) negated: Option<Box<RouteAddr>>,
 }
 
-pub(crate) struct Route {
+impl RouteAddr {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.empty && self.negated.is_none()
+    }
+
+    pub(crate) fn needs_geoip(&self) -> bool {
+        self.geoip.needs_reader() || matches!(&self.negated, Some(x) if x.needs_geoip())
+    }
+}
+
+pub(crate) struct Rule {
     pub(crate) tag: Vec<String>,
     pub(crate) network: Vec<String>,
     pub(crate) saddr: RouteAddr,
@@ -48,138 +165,349 @@ pub(crate) struct Route {
     pub(crate) daddr: RouteAddr,
     pub(crate) dport: Vec<usize>,
     pub(crate) dns_domain: RouteAddr,
+    pub(crate) process_name: Vec<String>,
+    pub(crate) process_path: Vec<String>,
+    pub(crate) uid: Vec<u32>,
+    pub(crate) gid: Vec<u32>,
+    pub(crate) sniff_domain: RouteAddr,
+    pub(crate) alpn: Vec<String>,
+    pub(crate) not: Option<Box<Rule>>,
+    pub(crate) any: Vec<Rule>,
+    pub(crate) all: Vec<Rule>,
+}
+
+impl Rule {
+    // process_name, process_path, uid or gid here or in a sub-rule
+    pub(crate) fn needs_owner(&self) -> bool {
+        !self.process_name.is_empty()
+            || !self.process_path.is_empty()
+            || !self.uid.is_empty()
+            || !self.gid.is_empty()
+            || matches!(&self.not, Some(x) if x.needs_owner())
+            || self.any.iter().chain(&self.all).any(Rule::needs_owner)
+    }
+
+    // process_name, process_path or gid, which need the pid of the socket
+    pub(crate) fn needs_pid(&self) -> bool {
+        !self.process_name.is_empty()
+            || !self.process_path.is_empty()
+            || !self.gid.is_empty()
+            || matches!(&self.not, Some(x) if x.needs_pid())
+            || self.any.iter().chain(&self.all).any(Rule::needs_pid)
+    }
+}
 
+pub(crate) struct Route {
+    pub(crate) rule: Rule,
     pub(crate) jump: Arc<dyn Out + Send + Sync>,
+    pub(crate) jump_tag: String,
+    pub(crate) hits: AtomicU64,
+    pub(crate) log: bool,
+    pub(crate) quota: Option<Quota>,
+    pub(crate) resolve_daddr: bool,
 }
 
-pub(crate) fn route_out_parse(root: &serde_json::Value) {
-    let mut jump_map = HashMap::new();
+// build new outs and routes, then replace the old ones at once.
+// on error nothing is replaced, flows keep the Arc of the out they got.
+pub(crate) fn route_out_parse(config: &Config) -> Result<(), ConfigError> {
+    let parsed = route_parse(config)?;
+    out_parse(config, parsed);
+
+    Ok(())
+}
+
+// rules parsed from a config, no out is built yet
+pub(crate) struct ParsedRoute {
+    geoip: Option<Arc<maxminddb::Reader<Vec<u8>>>>,
+    rules: Vec<Rule>,
+}
+
+// read the files of routes and check their jumps,
+// main runs it before the setting drops privileges and daemonizes
+pub(crate) fn route_parse(config: &Config) -> Result<ParsedRoute, ConfigError> {
+    let mut errors = Vec::new();
+
+    let geoip = match open_geoip(&config.setting.geoip) {
+        Ok(o) => o,
+        Err(e) => return Err(ConfigError(vec![e])),
+    };
+    // only needed while parsing
+    let geosite = match open_geosite(&config.setting.geosite) {
+        Ok(o) => o,
+        Err(e) => return Err(ConfigError(vec![e])),
+    };
+
+    // parse routes before any out is built
+    let mut rules = Vec::new();
+    for (index, route) in config.route.iter().enumerate() {
+        let path = format!("route[{}]", index);
+        let rule = parse_rule(
+            &route.rule(),
+            &path,
+            geosite.as_ref(),
+            geoip.is_some(),
+            &mut errors,
+        );
+        if !config.out.iter().any(|x| x.tag() == route.jump) {
+            errors.push(format!("{}.jump: out {} not found", path, route.jump));
+        }
+        if let Some(quota) = &route.quota {
+            if !quota.fallback.is_empty() && !config.out.iter().any(|x| x.tag() == quota.fallback) {
+                errors.push(format!(
+                    "{}.quota.fallback: out {} not found",
+                    path, quota.fallback
+                ));
+            }
+        }
+        rules.push(rule);
+    }
+    if !errors.is_empty() {
+        return Err(ConfigError(errors));
+    }
 
-    for iter in root["out"].as_array().expect("out not found") {
-        let out = match iter["protocol"].as_str().expect("protocol not found") {
-            "origin" => origin::Out::new(iter),
+    Ok(ParsedRoute { geoip, rules })
+}
+
+pub(crate) fn out_parse(config: &Config, parsed: ParsedRoute) {
+    let mut jump_map = HashMap::new();
+    let mut new_out = Vec::new();
+    for out_config in &config.out {
+        let out = match out_config {
+            OutConfig::Origin(config) => origin::Out::new(config),
             #[cfg(feature = "private")]
-            "stn" => stn::Out::new(iter),
-            "socks5" => socks5::Out::new(iter),
-            "http" => http::Out::new(iter),
-            "drop" => drop::Out::new(iter),
-            "dns" => dns::Out::new(iter),
-            protocol => panic!("protocol not support: {:?}", protocol),
+            OutConfig::Stn(config) => stn::Out::new(config),
+            OutConfig::Socks5(config) => socks5::Out::new(config),
+            OutConfig::Http(config) => http::Out::new(config),
+            OutConfig::Drop(config) => drop::Out::new(config),
+            OutConfig::Dns(config) => dns::Out::new(config),
         };
-        OUT.write().push(out.clone());
-
-        jump_map.insert(
-            iter["tag"]
-                .as_str()
-                .expect("route tag not found")
-                .to_string(),
-            out,
-        );
+        new_out.push((out_config.tag().to_string(), out.clone()));
+
+        jump_map.insert(out_config.tag().to_string(), out);
+    }
+
+    let mut new_route = Vec::new();
+    for (route, rule) in config.route.iter().zip(parsed.rules) {
+        new_route.push(Route {
+            rule,
+            jump: jump_map[&route.jump].clone(),
+            jump_tag: route.jump.clone(),
+            hits: AtomicU64::new(0),
+            log: route.log,
+            quota: route.quota.as_ref().map(|x| Quota::new(x, &jump_map)),
+            resolve_daddr: route.resolve_daddr.unwrap_or(config.setting.resolve_daddr)
+                == ResolveDaddr::IfNoDomainMatch,
+        });
     }
 
-    if root["route"].as_array().is_some() {
-        for iter in root["route"].as_array().unwrap() {
-            let route: RouteRaw = serde_json::from_value(iter.clone()).unwrap();
-            let saddr = parse_addr(&route.saddr);
-            let daddr = parse_addr(&route.daddr);
-            let dns_domain = parse_addr(&route.dns_domain);
-
-            ROUTE.write().push(Route {
-                tag: route.tag,
-                network: route.network,
-                saddr,
-                sport: route.sport,
-                daddr,
-                dport: route.dport,
-                dns_domain,
-                jump: jump_map
-                    .get(&route.jump)
-                    .expect("route jump not found")
-                    .clone(),
-            });
+    let mut out_write = OUT.write();
+    let mut route_write = ROUTE.write();
+    *out_write = new_out;
+    *route_write = new_route;
+    *GEOIP.write() = parsed.geoip;
+    TRACE.store(config.setting.route_log, Ordering::Relaxed);
+}
+
+// a route or a sub-rule at path, like "route[0].any[1]"
+pub(crate) fn parse_rule(
+    rule: &RuleConfig,
+    path: &str,
+    geosite: Option<&GeoSite>,
+    has_geoip: bool,
+    errors: &mut Vec<String>,
+) -> Rule {
+    let saddr = parse_addr(&rule.saddr, &format!("{}.saddr", path), geosite, errors);
+    let daddr = parse_addr(&rule.daddr, &format!("{}.daddr", path), geosite, errors);
+    let dns_domain = parse_addr(
+        &rule.dns_domain,
+        &format!("{}.dns_domain", path),
+        geosite,
+        errors,
+    );
+    let sniff_domain = parse_addr(
+        &rule.sniff_domain,
+        &format!("{}.sniff_domain", path),
+        geosite,
+        errors,
+    );
+    if !has_geoip {
+        for (name, addr) in [("saddr", &saddr), ("daddr", &daddr)].iter() {
+            if addr.needs_geoip() {
+                errors.push(format!("{}.{}: geoip needs setting.geoip", path, name));
+            }
         }
     }
+
+    Rule {
+        tag: rule.tag.clone(),
+        network: rule.network.clone(),
+        saddr,
+        sport: rule.sport.clone(),
+        daddr,
+        dport: rule.dport.clone(),
+        dns_domain,
+        process_name: rule.process_name.clone(),
+        process_path: rule.process_path.clone(),
+        uid: rule.uid.clone(),
+        gid: rule.gid.clone(),
+        sniff_domain,
+        alpn: rule.alpn.clone(),
+        not: rule.not.as_ref().map(|x| {
+            Box::new(parse_rule(
+                x,
+                &format!("{}.not", path),
+                geosite,
+                has_geoip,
+                errors,
+            ))
+        }),
+        any: rule
+            .any
+            .iter()
+            .enumerate()
+            .map(|(index, x)| {
+                parse_rule(
+                    x,
+                    &format!("{}.any[{}]", path, index),
+                    geosite,
+                    has_geoip,
+                    errors,
+                )
+            })
+            .collect(),
+        all: rule
+            .all
+            .iter()
+            .enumerate()
+            .map(|(index, x)| {
+                parse_rule(
+                    x,
+                    &format!("{}.all[{}]", path, index),
+                    geosite,
+                    has_geoip,
+                    errors,
+                )
+            })
+            .collect(),
+    }
 }
 
-fn parse_addr(addrs: &Vec<String>) -> RouteAddr {
+// invalid entries are reported as "path[index]: reason" and skipped
+pub(crate) fn parse_addr(
+    addrs: &[String],
+    path: &str,
+    geosite: Option<&GeoSite>,
+    errors: &mut Vec<String>,
+) -> RouteAddr {
+    // "!cidr 10.0.0.0/8"
+    let mut entries = Vec::new();
+    let mut negated_entries = Vec::new();
+    for (index, addr) in addrs.iter().enumerate() {
+        match addr.strip_prefix('!') {
+            Some(s) => negated_entries.push((index, s.trim_start())),
+            None => entries.push((index, addr.as_str())),
+        }
+    }
+
+    let mut route_addr = parse_addr_entries(&entries, path, geosite, errors);
+    if !negated_entries.is_empty() {
+        route_addr.negated = Some(Box::new(parse_addr_entries(
+            &negated_entries,
+            path,
+            geosite,
+            errors,
+        )));
+    }
+
+    route_addr
+}
+
+// (index in the route field, entry)
+fn parse_addr_entries(
+    addrs: &[(usize, &str)],
+    path: &str,
+    geosite: Option<&GeoSite>,
+    errors: &mut Vec<String>,
+) -> RouteAddr {
     let mut full_vec = Vec::new();
     let mut substring_vec = Vec::new();
     let mut domain_vec = Vec::new();
     let mut cidr4 = IpLookupTable::new();
     let mut cidr6 = IpLookupTable::new();
     let mut regex_vec = Vec::new();
+    let mut geoip = GeoIpSet::default();
 
-    for addr in addrs {
-        let single_addr_vec = if addr.contains("file ") {
-            let file = std::fs::File::open(addr[5..].to_string())
-                .expect(format!("failed to open {}", addr[5..].to_string()).as_str());
-            std::io::BufReader::new(file)
-                .lines()
-                .map(|x| x.unwrap())
-                .collect()
+    for (index, addr) in addrs.iter() {
+        // (path, entry, read from a file)
+        let single_addr_vec = if let Some(file) = addr.strip_prefix("file ") {
+            match read_addr_file(file) {
+                Ok(o) => o
+                    .into_iter()
+                    .enumerate()
+                    .map(|(line, x)| (format!("{}[{}] line {}", path, index, line + 1), x, true))
+                    .collect(),
+                Err(e) => {
+                    errors.push(format!("{}[{}]: {}", path, index, e));
+                    continue;
+                }
+            }
+        } else if let Some(value) = addr.strip_prefix("geosite ") {
+            let domains = match geosite {
+                Some(s) => s.expand(value.trim()),
+                None => Err("geosite needs setting.geosite".to_string()),
+            };
+            match domains {
+                Ok(o) => o
+                    .into_iter()
+                    .map(|x| (format!("{}[{}]", path, index), x, false))
+                    .collect(),
+                Err(e) => {
+                    errors.push(format!("{}[{}]: {}", path, index, e));
+                    continue;
+                }
+            }
         } else {
-            vec![addr.clone()]
+            vec![(format!("{}[{}]", path, index), addr.to_string(), false)]
         };
 
-        for single_addr in single_addr_vec {
+        for (single_path, single_addr, in_file) in single_addr_vec {
             let mut single_addr_split = single_addr.split_whitespace();
-            match single_addr_split.next().expect("invalid route addr") {
-                "full" => full_vec.push(format!(
-                    " {} ",
-                    single_addr_split.next().expect("invalid route addr")
-                )),
-                "substring" => substring_vec.push(
-                    single_addr_split
-                        .next()
-                        .expect("invalid route addr")
-                        .to_string(),
-                ),
-                "domain" => domain_vec.push(format!(
-                    " {} ",
-                    single_addr_split.next().expect("invalid route addr")
-                )),
-                "cidr" => {
-                    let mut cidr_split = single_addr_split
-                        .next()
-                        .expect("invalid route addr")
-                        .split("/");
-                    match cidr_split
-                        .next()
-                        .expect("invalid route cidr")
-                        .parse::<IpAddr>()
-                        .expect("invalid route cidr")
-                    {
-                        IpAddr::V4(ip) => {
-                            cidr4.insert(
-                                ip,
-                                cidr_split
-                                    .next()
-                                    .expect("invalid route cidr")
-                                    .parse()
-                                    .expect("invalid route cidr"),
-                                (),
-                            );
-                        }
-                        IpAddr::V6(ip) => {
-                            cidr6.insert(
-                                ip,
-                                cidr_split
-                                    .next()
-                                    .expect("invalid route cidr")
-                                    .parse()
-                                    .expect("invalid route cidr"),
-                                (),
-                            );
-                        }
+            let (kind, value) = match (single_addr_split.next(), single_addr_split.next()) {
+                (Some(kind), Some(value)) => (kind, value),
+                // empty line in file
+                (None, _) if in_file => continue,
+                _ => {
+                    errors.push(format!(
+                        "{}: invalid route addr {}",
+                        single_path, single_addr
+                    ));
+                    continue;
+                }
+            };
+
+            match kind {
+                "full" => full_vec.push(format!(" {} ", value)),
+                "substring" => substring_vec.push(value.to_string()),
+                "domain" => domain_vec.push(format!(" {} ", value)),
+                "cidr" => match parse_cidr(value) {
+                    Ok((IpAddr::V4(ip), masklen)) if masklen <= 32 => {
+                        cidr4.insert(ip, masklen, ());
+                    }
+                    Ok((IpAddr::V6(ip), masklen)) if masklen <= 128 => {
+                        cidr6.insert(ip, masklen, ());
+                    }
+                    _ => errors.push(format!("{}: invalid route cidr {}", single_path, value)),
+                },
+                "regex" => match regex::Regex::new(value) {
+                    Ok(_) => regex_vec.push(value.to_string()),
+                    Err(e) => errors.push(format!("{}: {}", single_path, e)),
+                },
+                "geoip" => {
+                    if let Err(e) = geoip.insert(value) {
+                        errors.push(format!("{}: {}", single_path, e));
                     }
                 }
-                "regex" => regex_vec.push(
-                    single_addr_split
-                        .next()
-                        .expect("invalid route addr")
-                        .to_string(),
-                ),
-                invalid => warn!("{} not support", invalid),
+                invalid => errors.push(format!("{}: {} not support", single_path, invalid)),
             };
         }
     }
@@ -190,7 +518,56 @@ fn parse_addr(addrs: &Vec<String>) -> RouteAddr {
         domain: aho_corasick::AhoCorasick::new(domain_vec),
         cidr4,
         cidr6,
+        // every pattern has been compiled above
         regex: regex::RegexSet::new(regex_vec).expect("can't generate RegexSet"),
-        empty: addrs.len() == 0,
+        geoip,
+        empty: addrs.is_empty(),
+        negated: None,
     }
 }
+
+fn read_addr_file(file_path: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let file = std::fs::File::open(file_path)
+        .map_err(|e| format!("failed to open {}: {}", file_path, e))?;
+
+    Ok(std::io::BufReader::new(file)
+        .lines()
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("failed to read {}: {}", file_path, e))?)
+}
+
+// "10.0.0.0/8" -> 10.0.0.0 8
+fn parse_cidr(cidr: &str) -> Result<(IpAddr, u32), Box<dyn std::error::Error>> {
+    let mut cidr_split = cidr.split('/');
+    let ip = cidr_split.next().ok_or("invalid cidr")?.parse()?;
+    let masklen = cidr_split.next().ok_or("invalid cidr")?.parse()?;
+
+    Ok((ip, masklen))
+}
+
+#[test]
+fn test_parse_addr_error() {
+    let mut errors = Vec::new();
+    parse_addr(
+        &[
+            "domain a.com".to_string(),
+            "cidr 10.0.0.0/33".to_string(),
+            "regex (".to_string(),
+            "ip 1.1.1.1".to_string(),
+            "file /nonexistent".to_string(),
+            "geoip china".to_string(),
+            "geosite google".to_string(),
+        ],
+        "route[0].daddr",
+        None,
+        &mut errors,
+    );
+
+    assert_eq!(errors.len(), 6);
+    assert!(errors[0].starts_with("route[0].daddr[1]: invalid route cidr"));
+    assert!(errors[1].starts_with("route[0].daddr[2]: "));
+    assert!(errors[2].starts_with("route[0].daddr[3]: ip not support"));
+    assert!(errors[3].starts_with("route[0].daddr[4]: failed to open"));
+    assert!(errors[4].starts_with("route[0].daddr[5]: invalid route geoip"));
+    assert!(errors[5].starts_with("route[0].daddr[6]: geosite needs setting.geosite"));
+}
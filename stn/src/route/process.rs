@@ -0,0 +1,234 @@
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use std::{
+    collections::VecDeque,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+// udp routes every packet, so its lookups are kept for a while
+const OWNER_TTL: Duration = Duration::from_secs(10);
+
+// (looked up at, owner)
+type CachedOwner = (Instant, Option<Arc<SocketOwner>>);
+
+// pids whose fds are read first, as a process usually opens many flows
+const RECENT_PIDS_MAX: usize = 64;
+
+lazy_static::lazy_static! {
+    // (network, saddr, with pid) -> owner of the local socket
+    static ref OWNERS: DashMap<(String, String, bool), CachedOwner> = DashMap::new();
+    // latest first
+    static ref RECENT_PIDS: Mutex<VecDeque<u32>> = Mutex::new(VecDeque::new());
+}
+
+// the local process which opened a flow
+#[derive(Debug, Default)]
+pub(crate) struct SocketOwner {
+    pub(crate) uid: u32,
+    // None if the socket is not found in any fd of /proc, or no route needs the pid
+    pub(crate) gid: Option<u32>,
+    pub(crate) name: Option<String>,
+    pub(crate) path: Option<String>,
+}
+
+// None for a remote source or one routed again, like "[tag]:[id]".
+// /proc is read on a blocking thread, once per tcp flow as its sport is new.
+// uid comes from /proc/net, the fds of processes are only read with_pid, for name, path and gid
+pub(crate) async fn socket_owner(
+    network: &str,
+    saddr: &str,
+    with_pid: bool,
+) -> Option<Arc<SocketOwner>> {
+    let key = (network.to_string(), saddr.to_string(), with_pid);
+    if let Some(s) = OWNERS.get(&key) {
+        if s.0.elapsed() < OWNER_TTL {
+            return s.1.clone();
+        }
+    }
+
+    let saddr = saddr.parse::<SocketAddr>().ok()?;
+    let network = key.0.clone();
+    let owner = tokio::task::spawn_blocking(move || lookup(&network, saddr, with_pid))
+        .await
+        .ok()
+        .flatten()
+        .map(Arc::new);
+
+    if key.0 == "udp" {
+        if OWNERS.len() > 4096 {
+            OWNERS.retain(|_, x| x.0.elapsed() < OWNER_TTL);
+        }
+        OWNERS.insert(key, (Instant::now(), owner.clone()));
+    }
+
+    owner
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn lookup(network: &str, saddr: SocketAddr, with_pid: bool) -> Option<SocketOwner> {
+    let (uid, inode) = ["", "6"].iter().find_map(|suffix| {
+        let table = std::fs::read_to_string(format!("/proc/net/{}{}", network, suffix)).ok()?;
+        table
+            .lines()
+            .skip(1)
+            .filter_map(parse_proc_net_line)
+            .find(|(local, _, _)| {
+                local.port() == saddr.port()
+                    && (unmap(local.ip()) == unmap(saddr.ip())
+                        // an unconnected udp socket
+                        || (network == "udp" && local.ip().is_unspecified()))
+            })
+            .map(|(_, uid, inode)| (uid, inode))
+    })?;
+
+    let mut owner = SocketOwner {
+        uid,
+        ..Default::default()
+    };
+    if !with_pid {
+        return Some(owner);
+    }
+    if let Some(pid) = find_pid(inode) {
+        owner.name = std::fs::read_to_string(format!("/proc/{}/comm", pid))
+            .ok()
+            .map(|x| x.trim_end().to_string());
+        owner.path = std::fs::read_link(format!("/proc/{}/exe", pid))
+            .ok()
+            .map(|x| x.to_string_lossy().to_string());
+        // "Gid: real effective saved fs"
+        owner.gid = std::fs::read_to_string(format!("/proc/{}/status", pid))
+            .ok()
+            .and_then(|status| {
+                status
+                    .lines()
+                    .find_map(|x| x.strip_prefix("Gid:"))?
+                    .split_whitespace()
+                    .nth(1)?
+                    .parse()
+                    .ok()
+            });
+    }
+
+    Some(owner)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn lookup(_network: &str, _saddr: SocketAddr, _with_pid: bool) -> Option<SocketOwner> {
+    None
+}
+
+// the process holding "socket:[inode]", reading other users' fds needs root or CAP_SYS_PTRACE.
+// recent pids are tried before all of /proc
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn find_pid(inode: u64) -> Option<u32> {
+    let link = format!("socket:[{}]", inode);
+    let recent: Vec<u32> = RECENT_PIDS.lock().iter().copied().collect();
+    let pid = recent
+        .iter()
+        .copied()
+        .find(|pid| holds(*pid, &link))
+        .or_else(|| {
+            std::fs::read_dir("/proc")
+                .ok()?
+                .flatten()
+                .find_map(|entry| {
+                    let pid: u32 = entry.file_name().to_str()?.parse().ok()?;
+                    (!recent.contains(&pid) && holds(pid, &link)).then_some(pid)
+                })
+        })?;
+
+    let mut recent = RECENT_PIDS.lock();
+    recent.retain(|x| *x != pid);
+    recent.push_front(pid);
+    recent.truncate(RECENT_PIDS_MAX);
+
+    Some(pid)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn holds(pid: u32, link: &str) -> bool {
+    match std::fs::read_dir(format!("/proc/{}/fd", pid)) {
+        Ok(o) => o
+            .flatten()
+            .any(|fd| matches!(std::fs::read_link(fd.path()), Ok(x) if x.as_os_str() == link)),
+        Err(_) => false,
+    }
+}
+
+// "   1: 0100007F:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 12345 ..."
+// -> (local address, uid, inode)
+fn parse_proc_net_line(line: &str) -> Option<(SocketAddr, u32, u64)> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 10 {
+        return None;
+    }
+
+    let (ip, port) = fields[1].split_at(fields[1].find(':')?);
+    let port = u16::from_str_radix(&port[1..], 16).ok()?;
+    // 32 bit words in host byte order
+    let mut words = Vec::new();
+    for index in (0..ip.len()).step_by(8) {
+        words.push(
+            u32::from_str_radix(ip.get(index..index + 8)?, 16)
+                .ok()?
+                .to_ne_bytes(),
+        );
+    }
+    let ip = match words.as_slice() {
+        [a] => IpAddr::from(*a),
+        [a, b, c, d] => {
+            let mut octets = [0u8; 16];
+            for (index, word) in [a, b, c, d].iter().enumerate() {
+                octets[index * 4..index * 4 + 4].copy_from_slice(*word);
+            }
+            IpAddr::from(octets)
+        }
+        _ => return None,
+    };
+
+    Some((
+        SocketAddr::new(ip, port),
+        fields[7].parse().ok()?,
+        fields[9].parse().ok()?,
+    ))
+}
+
+// "::ffff:10.0.0.2" -> "10.0.0.2"
+fn unmap(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => ip
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(ip)),
+        ip => ip,
+    }
+}
+
+#[test]
+fn test_parse_proc_net_line() {
+    let local = |addr: &str| -> SocketAddr {
+        let mut octets = match addr.parse::<SocketAddr>().unwrap().ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        // as the kernel prints it
+        let hex: String = octets
+            .chunks_mut(4)
+            .map(|x| format!("{:08X}", u32::from_ne_bytes([x[0], x[1], x[2], x[3]])))
+            .collect();
+        let line = format!(
+            "   0: {}:{:04X} 00000000:0000 01 00000000:00000000 00:00000000 00000000  1000        0 4242 1 0000000000000000 20 4 30 10 -1",
+            hex,
+            addr.parse::<SocketAddr>().unwrap().port()
+        );
+        let (local, uid, inode) = parse_proc_net_line(&line).unwrap();
+        assert_eq!((uid, inode), (1000, 4242));
+        local
+    };
+
+    assert_eq!(local("127.0.0.1:8080").to_string(), "127.0.0.1:8080");
+    assert_eq!(local("[fe80::1:2]:53").to_string(), "[fe80::1:2]:53");
+    assert!(parse_proc_net_line("  sl  local_address rem_address   st").is_none());
+}
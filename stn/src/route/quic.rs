@@ -0,0 +1,332 @@
+use super::{parse_client_hello, ByteReader, Sniff};
+use ring::{aead, hkdf};
+use tokio::time::Instant;
+
+// rfc 9001 5.2, quic v1
+const INITIAL_SALT: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad,
+    0xcc, 0xbb, 0x7f, 0x0a,
+];
+const VERSION_1: [u8; 4] = [0, 0, 0, 1];
+
+// long header, fixed bit, Initial, version 1
+pub(crate) fn is_quic_initial(buf: &[u8]) -> bool {
+    buf.len() > 5 && buf[0] & 0xf0 == 0xc0 && buf[1..5] == VERSION_1
+}
+
+// the Initial datagrams sent to a daddr while its ClientHello is incomplete
+pub(crate) struct QuicPending {
+    pub(crate) datagrams: Vec<Vec<u8>>,
+    // (offset, data) of CRYPTO frames, each datagram is decrypted once
+    crypto: Vec<(usize, Vec<u8>)>,
+    pub(crate) len: usize,
+    pub(crate) deadline: Instant,
+}
+
+impl QuicPending {
+    pub(crate) fn new(deadline: Instant) -> Self {
+        QuicPending {
+            datagrams: Vec::new(),
+            crypto: Vec::new(),
+            len: 0,
+            deadline,
+        }
+    }
+
+    // the ClientHello of every datagram held so far
+    pub(crate) fn push(&mut self, datagram: Vec<u8>) -> Sniff {
+        let sniff = if read_datagram(&datagram, &mut self.crypto) {
+            parse_crypto(&mut self.crypto)
+        } else {
+            Sniff::Unknown
+        };
+        self.len += datagram.len();
+        self.datagrams.push(datagram);
+
+        sniff
+    }
+}
+
+// the ClientHello of the Initial packets coalesced in one datagram
+pub(crate) fn sniff_quic(datagram: &[u8]) -> Sniff {
+    let mut crypto = Vec::new();
+    if !read_datagram(datagram, &mut crypto) {
+        return Sniff::Unknown;
+    }
+
+    parse_crypto(&mut crypto)
+}
+
+// false if the datagram has no Initial packet, or one that fails to decrypt
+fn read_datagram(datagram: &[u8], crypto: &mut Vec<(usize, Vec<u8>)>) -> bool {
+    let mut rest = datagram;
+    let mut initial = false;
+
+    // padding or a short header packet ends the coalesced ones
+    while matches!(rest.first(), Some(x) if x & 0x80 != 0) {
+        let (packet, next) = match split_packet(rest) {
+            Some(s) => s,
+            None => break,
+        };
+        rest = next;
+        if !is_quic_initial(packet) {
+            continue;
+        }
+        initial = true;
+        match decrypt_initial(packet) {
+            Some(payload) => {
+                read_crypto_frames(&payload, crypto);
+            }
+            None => return false,
+        }
+    }
+
+    initial
+}
+
+// CRYPTO frames may come in any order, the ClientHello needs them from offset 0
+fn parse_crypto(crypto: &mut [(usize, Vec<u8>)]) -> Sniff {
+    crypto.sort_by_key(|x| x.0);
+    let mut handshake = Vec::new();
+    for (offset, data) in crypto.iter() {
+        if *offset > handshake.len() {
+            break;
+        }
+        if offset + data.len() > handshake.len() {
+            handshake.extend_from_slice(&data[handshake.len() - offset..]);
+        }
+    }
+
+    match parse_client_hello(&handshake) {
+        Sniff::Found(mut sniffed) => {
+            sniffed.protocol = "quic";
+            Sniff::Found(sniffed)
+        }
+        sniff => sniff,
+    }
+}
+
+// a long header packet and the rest of the datagram
+fn split_packet(buf: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (_, _, end) = parse_long_header(buf)?;
+    Some(buf.split_at(end))
+}
+
+// (dcid length, packet number offset, end of packet), the dcid starts at 6
+fn parse_long_header(buf: &[u8]) -> Option<(usize, usize, usize)> {
+    let mut header = ByteReader(buf);
+    // first byte, version
+    header.take(5)?;
+    let dcid_len = header.u8()? as usize;
+    header.take(dcid_len)?;
+    let n = header.u8()? as usize;
+    header.take(n)?;
+    // only Initial has a token
+    if buf[0] & 0x30 == 0 {
+        let n = header.varint()? as usize;
+        header.take(n)?;
+    }
+    let len = header.varint()? as usize;
+    let offset = buf.len() - header.0.len();
+    let end = offset.checked_add(len)?;
+    if end > buf.len() {
+        return None;
+    }
+
+    Some((dcid_len, offset, end))
+}
+
+struct InitialKeys {
+    key: aead::LessSafeKey,
+    iv: [u8; 12],
+    hp: aead::quic::HeaderProtectionKey,
+}
+
+// client_initial_secret of the destination connection id
+fn client_initial_keys(dcid: &[u8]) -> Option<InitialKeys> {
+    let initial_secret = hkdf::Salt::new(hkdf::HKDF_SHA256, &INITIAL_SALT).extract(dcid);
+    let mut secret = [0; 32];
+    expand_label(&initial_secret, "client in", &mut secret)?;
+    let secret = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &secret);
+
+    let mut key = [0; 16];
+    let mut iv = [0; 12];
+    let mut hp = [0; 16];
+    expand_label(&secret, "quic key", &mut key)?;
+    expand_label(&secret, "quic iv", &mut iv)?;
+    expand_label(&secret, "quic hp", &mut hp)?;
+
+    Some(InitialKeys {
+        key: aead::LessSafeKey::new(aead::UnboundKey::new(&aead::AES_128_GCM, &key).ok()?),
+        iv,
+        hp: aead::quic::HeaderProtectionKey::new(&aead::quic::AES_128, &hp).ok()?,
+    })
+}
+
+struct Len(usize);
+
+impl hkdf::KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+// HKDF-Expand-Label of tls 1.3 with an empty context
+fn expand_label(secret: &hkdf::Prk, label: &str, out: &mut [u8]) -> Option<()> {
+    let label = format!("tls13 {}", label);
+    let mut info = (out.len() as u16).to_be_bytes().to_vec();
+    info.push(label.len() as u8);
+    info.extend_from_slice(label.as_bytes());
+    info.push(0);
+
+    let info = [info.as_slice()];
+    secret.expand(&info, Len(out.len())).ok()?.fill(out).ok()
+}
+
+// remove header protection, then open the payload
+fn decrypt_initial(packet: &[u8]) -> Option<Vec<u8>> {
+    let (dcid_len, pn_offset, end) = parse_long_header(packet)?;
+    let keys = client_initial_keys(packet.get(6..6 + dcid_len)?)?;
+
+    let sample = packet.get(pn_offset + 4..pn_offset + 20)?;
+    let mask = keys.hp.new_mask(sample).ok()?;
+    let mut header = packet[..pn_offset].to_vec();
+    header[0] ^= mask[0] & 0x0f;
+    let pn_len = (header[0] & 0x03) as usize + 1;
+    let mut nonce = keys.iv;
+    for index in 0..pn_len {
+        let byte = packet.get(pn_offset + index)? ^ mask[1 + index];
+        header.push(byte);
+        // the first packets, so the truncated number is the full one
+        nonce[12 - pn_len + index] ^= byte;
+    }
+
+    let mut payload = packet.get(pn_offset + pn_len..end)?.to_vec();
+    let len = keys
+        .key
+        .open_in_place(
+            aead::Nonce::assume_unique_for_key(nonce),
+            aead::Aad::from(&header),
+            &mut payload,
+        )
+        .ok()?
+        .len();
+    payload.truncate(len);
+
+    Some(payload)
+}
+
+// (offset, data) of CRYPTO frames, stops at a frame an Initial packet doesn't carry
+fn read_crypto_frames(payload: &[u8], crypto: &mut Vec<(usize, Vec<u8>)>) -> Option<()> {
+    let mut payload = ByteReader(payload);
+    while !payload.0.is_empty() {
+        match payload.varint()? {
+            // PADDING, PING
+            0x00 | 0x01 => {}
+            // ACK, ACK with ECN counts
+            kind @ (0x02 | 0x03) => {
+                // largest acknowledged, delay, range count, first range
+                payload.varint()?;
+                payload.varint()?;
+                let ranges = payload.varint()?;
+                payload.varint()?;
+                let ecn_counts = if kind == 0x03 { 3 } else { 0 };
+                for _ in 0..ranges * 2 + ecn_counts {
+                    payload.varint()?;
+                }
+            }
+            // CRYPTO
+            0x06 => {
+                let offset = payload.varint()? as usize;
+                let n = payload.varint()? as usize;
+                crypto.push((offset, payload.take(n)?.to_vec()));
+            }
+            _ => return None,
+        }
+    }
+
+    Some(())
+}
+
+// an Initial packet carrying handshake bytes at offset, padded to 1200 bytes, for stn route and tests
+pub(crate) fn build_quic_initial(dcid: &[u8], offset: usize, handshake: &[u8]) -> Vec<u8> {
+    let keys = client_initial_keys(dcid).expect("initial keys");
+
+    // CRYPTO with a 4 byte offset and a 2 byte length, then PADDING
+    let mut payload = vec![0x06];
+    payload.extend_from_slice(&(0x8000_0000 | offset as u32).to_be_bytes());
+    payload.extend_from_slice(&(0x4000 | handshake.len() as u16).to_be_bytes());
+    payload.extend_from_slice(handshake);
+    payload.resize(payload.len().max(1200), 0);
+
+    // 4 byte packet number 0
+    let mut packet = vec![0xc3];
+    packet.extend_from_slice(&VERSION_1);
+    packet.push(dcid.len() as u8);
+    packet.extend_from_slice(dcid);
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(&(0x4000 | (4 + payload.len() + 16) as u16).to_be_bytes());
+    let pn_offset = packet.len();
+    packet.extend_from_slice(&[0; 4]);
+
+    keys.key
+        .seal_in_place_append_tag(
+            aead::Nonce::assume_unique_for_key(keys.iv),
+            aead::Aad::from(packet.clone()),
+            &mut payload,
+        )
+        .expect("seal");
+    packet.extend(payload);
+
+    let mask = keys
+        .hp
+        .new_mask(&packet[pn_offset + 4..pn_offset + 20])
+        .expect("mask");
+    packet[0] ^= mask[0] & 0x0f;
+    for index in 0..4 {
+        packet[pn_offset + index] ^= mask[1 + index];
+    }
+
+    packet
+}
+
+#[test]
+fn test_sniff_quic() {
+    // rfc 9001 appendix A.1
+    let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+    let initial_secret = hkdf::Salt::new(hkdf::HKDF_SHA256, &INITIAL_SALT).extract(&dcid);
+    let mut secret = [0; 32];
+    expand_label(&initial_secret, "client in", &mut secret).unwrap();
+    let secret = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &secret);
+    let mut iv = [0; 12];
+    expand_label(&secret, "quic iv", &mut iv).unwrap();
+    assert_eq!(
+        iv,
+        [0xfa, 0x04, 0x4b, 0x2f, 0x42, 0xa3, 0xfd, 0x3b, 0x46, 0xfb, 0x25, 0x5c]
+    );
+
+    let handshake = super::build_client_hello("a.com", &["h3"]);
+    let packet = build_quic_initial(&dcid, 0, &handshake);
+    assert!(is_quic_initial(&packet));
+    assert!(matches!(
+        sniff_quic(&packet),
+        Sniff::Found(x) if x.domain == "a.com" && x.protocol == "quic" && x.alpn == ["h3"]
+    ));
+
+    // a ClientHello split into two padded datagrams, received in reverse order
+    let mut first = build_quic_initial(&dcid, 0, &handshake[..20]);
+    let mut second = build_quic_initial(&dcid, 20, &handshake[20..]);
+    first.extend([0; 32]);
+    second.extend([0; 32]);
+    assert_eq!(sniff_quic(&first), Sniff::Incomplete);
+    let mut pending = QuicPending::new(Instant::now());
+    assert_eq!(pending.push(second), Sniff::Incomplete);
+    assert!(matches!(pending.push(first), Sniff::Found(x) if x.domain == "a.com"));
+    assert_eq!(pending.datagrams.len(), 2);
+
+    let mut tampered = packet;
+    let last = tampered.len() - 1;
+    tampered[last] ^= 1;
+    assert_eq!(sniff_quic(&tampered), Sniff::Unknown);
+    assert_eq!(sniff_quic(b"\x40short header"), Sniff::Unknown);
+}
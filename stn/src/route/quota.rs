@@ -0,0 +1,283 @@
+use super::Out;
+use crate::misc::{civil_from_days, days_from_civil, split_addr_str};
+use dashmap::DashMap;
+use log::*;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+lazy_static::lazy_static! {
+    // (quota tag, source ip or "") -> usage, kept across reloads
+    static ref USAGE: DashMap<(String, String), Arc<Usage>> = DashMap::new();
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct QuotaConfig {
+    // routes with the same tag share the usage
+    pub(crate) tag: String,
+    // bytes of both directions
+    pub(crate) limit: u64,
+    #[serde(default)]
+    pub(crate) period: Period,
+    #[serde(default)]
+    pub(crate) per_source: bool,
+    // out tag when exhausted, empty to drop
+    #[serde(default)]
+    pub(crate) fallback: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Period {
+    Day,
+    #[default]
+    Month,
+}
+
+impl Period {
+    // unix seconds the current period began, UTC
+    fn start(self, unix_secs: u64) -> u64 {
+        let days = (unix_secs / 86400) as i64;
+        let days = match self {
+            Period::Day => days,
+            Period::Month => {
+                let (year, month, _) = civil_from_days(days);
+                days_from_civil(year, month, 1)
+            }
+        };
+
+        days as u64 * 86400
+    }
+}
+
+struct Usage {
+    // (quota tag, source ip or "") for the quota hook
+    key: (String, String),
+    // unix seconds of the period the bytes belong to
+    start: AtomicU64,
+    used: AtomicU64,
+}
+
+impl Usage {
+    // restart from 0 in a new period
+    fn used(&self, start: u64) -> u64 {
+        let last = self.start.load(Ordering::Relaxed);
+        if last != start
+            && self
+                .start
+                .compare_exchange(last, start, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.used.store(0, Ordering::Relaxed);
+        }
+
+        self.used.load(Ordering::Relaxed)
+    }
+}
+
+pub(crate) struct Quota {
+    pub(crate) config: QuotaConfig,
+    // (tag, out) of config.fallback, or a drop out
+    pub(crate) fallback: (String, Arc<dyn Out + Send + Sync>),
+}
+
+impl Quota {
+    // outs by tag, the fallback is a drop out if not among them
+    pub(crate) fn new(
+        config: &QuotaConfig,
+        outs: &HashMap<String, Arc<dyn Out + Send + Sync>>,
+    ) -> Self {
+        let fallback = match outs.get(&config.fallback) {
+            Some(out) => (config.fallback.clone(), out.clone()),
+            None => (
+                "drop".to_string(),
+                crate::drop::Out::new(&crate::drop::OutConfig {
+                    tag: "drop".to_string(),
+                }),
+            ),
+        };
+
+        Quota {
+            config: config.clone(),
+            fallback,
+        }
+    }
+
+    // None once exhausted
+    pub(crate) fn charge(&self, saddr: &str) -> Option<Charge> {
+        let source = match (self.config.per_source, split_addr_str(saddr)) {
+            (true, Ok((ip, _))) => ip,
+            _ => String::new(),
+        };
+        let key = (self.config.tag.clone(), source);
+        let usage = USAGE
+            .entry(key.clone())
+            .or_insert_with(|| {
+                Arc::new(Usage {
+                    key,
+                    start: AtomicU64::new(0),
+                    used: AtomicU64::new(0),
+                })
+            })
+            .clone();
+
+        let charge = Charge {
+            usage,
+            limit: self.config.limit,
+            period: self.config.period,
+        };
+        if charge.add(0) {
+            Some(charge)
+        } else {
+            None
+        }
+    }
+}
+
+// the usage a flow adds its bytes to
+#[derive(Clone)]
+pub(crate) struct Charge {
+    usage: Arc<Usage>,
+    limit: u64,
+    period: Period,
+}
+
+impl Charge {
+    // the same for charges of one usage
+    pub(crate) fn id(&self) -> usize {
+        Arc::as_ptr(&self.usage) as usize
+    }
+
+    // false once the limit is reached
+    pub(crate) fn add(&self, len: u64) -> bool {
+        let start = self.period.start(unix_secs());
+        let used = self.usage.used(start);
+        if len == 0 {
+            return used < self.limit;
+        }
+
+        let used = self.usage.used.fetch_add(len, Ordering::Relaxed) + len;
+        if used < self.limit {
+            return true;
+        }
+
+        // only the flow crossing the limit reports it
+        if used - len < self.limit {
+            let (tag, source) = &self.usage.key;
+            info!("quota {} exhausted {}", tag, source);
+            crate::hook::emit("quota", || {
+                json!({
+                    "quota": tag,
+                    "source": source,
+                    "limit": self.limit,
+                    "period": self.period,
+                })
+            });
+        }
+
+        false
+    }
+}
+
+// one entry of setting.quota_state
+#[derive(Serialize, Deserialize)]
+struct UsageState {
+    tag: String,
+    source: String,
+    start: u64,
+    used: u64,
+}
+
+pub(crate) fn load_quota_state(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if path.is_empty() || !std::path::Path::new(path).exists() {
+        return Ok(());
+    }
+
+    let states: Vec<UsageState> = serde_json::from_slice(&std::fs::read(path)?)?;
+    for state in states {
+        let key = (state.tag, state.source);
+        USAGE.insert(
+            key.clone(),
+            Arc::new(Usage {
+                key,
+                start: AtomicU64::new(state.start),
+                used: AtomicU64::new(state.used),
+            }),
+        );
+    }
+
+    Ok(())
+}
+
+pub(crate) fn save_quota_state(path: &str) {
+    if path.is_empty() {
+        return;
+    }
+
+    if let Err(e) = write_quota_state(path) {
+        warn!("quota_state {} {}", path, e);
+    }
+}
+
+// replace the file at once, a crash never leaves half of it
+fn write_quota_state(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let states: Vec<UsageState> = USAGE
+        .iter()
+        .map(|x| UsageState {
+            tag: x.key().0.clone(),
+            source: x.key().1.clone(),
+            start: x.value().start.load(Ordering::Relaxed),
+            used: x.value().used.load(Ordering::Relaxed),
+        })
+        .collect();
+
+    let temp = format!("{}.tmp", path);
+    std::fs::write(&temp, serde_json::to_vec(&states)?)?;
+    std::fs::rename(&temp, path)?;
+
+    Ok(())
+}
+
+pub(crate) async fn save_quota_state_every_minute(path: String) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        save_quota_state(&path);
+    }
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+}
+
+#[test]
+fn test_quota() {
+    assert_eq!(Period::Month.start(1614834000), 1614556800);
+    assert_eq!(Period::Day.start(1614834000), 1614816000);
+
+    let quota = Quota::new(
+        &QuotaConfig {
+            tag: "test_quota".to_string(),
+            limit: 100,
+            period: Period::Day,
+            per_source: true,
+            fallback: String::new(),
+        },
+        &HashMap::new(),
+    );
+    let charge = quota.charge("10.0.0.2:5000").unwrap();
+    assert!(charge.add(60));
+    assert!(!charge.add(40));
+    assert!(quota.charge("10.0.0.2:6000").is_none());
+    assert!(quota.charge("10.0.0.3:5000").is_some());
+}
@@ -1,113 +1,525 @@
 use super::{
-    parse::{Route, RouteAddr},
-    Out,
+    parse::{Route, RouteAddr, Rule},
+    socket_owner, Charge, Out, Quota, Sniffed, SocketOwner,
 };
 use crate::misc::split_addr_str;
 use lazy_static::lazy_static;
 use log::*;
 use parking_lot::RwLock;
-use std::{net::IpAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 use trust_dns_proto::op::Message;
 
 lazy_static! {
-    pub(crate) static ref OUT: RwLock<Vec<Arc<dyn Out + Send + Sync>>> = RwLock::new(Vec::new());
+    pub(crate) static ref OUT: RwLock<Vec<(String, Arc<dyn Out + Send + Sync>)>> =
+        RwLock::new(Vec::new());
     pub(crate) static ref ROUTE: RwLock<Vec<Route>> = RwLock::new(Vec::new());
+    // flows which fell through to the default out
+    static ref DEFAULT_HITS: AtomicU64 = AtomicU64::new(0);
+}
+
+// setting.route_log, log every flow as if each route had "log": true
+pub(crate) static TRACE: AtomicBool = AtomicBool::new(false);
+
+// domains of a udp association resolved once, kept while they get packets
+const RESOLVED_MAX: usize = 1024;
+const RESOLVED_IDLE: Duration = Duration::from_secs(60);
+
+// the out a flow takes
+pub(crate) struct Jump {
+    pub(crate) out: Arc<dyn Out + Send + Sync>,
+    pub(crate) tag: String,
+    // None for the default out
+    pub(crate) index: Option<usize>,
+    // why the route matched, only if logged
+    pub(crate) matched: Option<String>,
+    // route.quota if not exhausted
+    pub(crate) quota: Option<Charge>,
+}
+
+// what routing looks up once, per tcp flow or per udp association, while every packet is matched
+#[derive(Default)]
+pub(crate) struct RouteCache {
+    // quota tag -> charge, None once exhausted
+    charges: HashMap<String, Option<Charge>>,
+    // domain daddr -> (ip of resolve_daddr, None if it failed; last used)
+    resolved: HashMap<String, (Option<String>, Instant)>,
+}
+
+impl RouteCache {
+    // the usage is looked up once, later calls only check it
+    fn charge(&mut self, quota: &Quota, saddr: &str) -> Option<Charge> {
+        if !self.charges.contains_key(&quota.config.tag) {
+            self.charges
+                .insert(quota.config.tag.clone(), quota.charge(saddr));
+        }
+        let charge = self.charges.get_mut(&quota.config.tag)?;
+        if matches!(charge, Some(x) if !x.add(0)) {
+            *charge = None;
+        }
+        charge.clone()
+    }
+
+    async fn resolve(&mut self, daddr: &String) -> Option<String> {
+        if !needs_resolve(daddr) {
+            return None;
+        }
+        if let Some(x) = self.resolved.get_mut(daddr) {
+            if x.1.elapsed() < RESOLVED_IDLE {
+                x.1 = Instant::now();
+                return x.0.clone();
+            }
+        }
+
+        let resolved = resolve_daddr(daddr).await;
+        if self.resolved.len() >= RESOLVED_MAX {
+            self.resolved.retain(|_, x| x.1.elapsed() < RESOLVED_IDLE);
+        }
+        if self.resolved.len() < RESOLVED_MAX {
+            self.resolved
+                .insert(daddr.clone(), (resolved.clone(), Instant::now()));
+        }
+        resolved
+    }
 }
 
-pub(crate) fn find_out(
+impl Jump {
+    // once per flow, not per udp packet
+    pub(crate) fn log(&self, tag: &str, network: &str, saddr: &str, daddr: &str) {
+        if let Some(matched) = &self.matched {
+            let route = match self.index {
+                Some(index) => format!("route[{}] hit: {}", index, matched),
+                None => matched.clone(),
+            };
+            info!(
+                "{} {} {} -> {} {} -> {}",
+                tag, network, saddr, daddr, route, self.tag
+            );
+        }
+    }
+}
+
+// the first matching route, then the routes with "resolve_daddr" again with the ip of a domain daddr
+pub(crate) async fn find_out_resolved(
     tag: String,
     network: String,
     saddr: String,
     daddr: String,
-    udp_buf: &[u8],
-) -> Arc<dyn Out + Send + Sync> {
-    for route_iter in &*ROUTE.read() {
-        if route_iter.tag.len() != 0 && !route_iter.tag.contains(&tag) {
-            continue;
+    buf: &[u8],
+    sniffed: Option<&Sniffed>,
+    cache: &mut RouteCache,
+) -> Jump {
+    // outside of the route lock
+    let (needs_owner, needs_pid) = {
+        let route = ROUTE.read();
+        (
+            route.iter().any(|x| x.rule.needs_owner()),
+            route.iter().any(|x| x.rule.needs_pid()),
+        )
+    };
+    let owner = if needs_owner {
+        socket_owner(&network, &saddr, needs_pid).await
+    } else {
+        None
+    };
+    let inspected = Inspected {
+        buf,
+        owner: owner.as_deref(),
+        sniffed,
+    };
+
+    if let Some(jump) = find_route(&tag, &network, &saddr, &daddr, &inspected, false, cache) {
+        return jump;
+    }
+
+    if let Some(resolved) = cache.resolve(&daddr).await {
+        if let Some(mut jump) =
+            find_route(&tag, &network, &saddr, &resolved, &inspected, true, cache)
+        {
+            jump.matched = jump
+                .matched
+                .map(|x| format!("{}, daddr resolved to {}", x, resolved));
+            return jump;
         }
+    }
 
-        if route_iter.network.len() != 0 && !route_iter.network.contains(&network) {
+    default_jump()
+}
+
+// a domain daddr while any route has "resolve_daddr"
+fn needs_resolve(daddr: &str) -> bool {
+    match split_addr_str(daddr) {
+        Ok((domain, _)) if domain.parse::<IpAddr>().is_err() => {
+            ROUTE.read().iter().any(|x| x.resolve_daddr)
+        }
+        _ => false,
+    }
+}
+
+// the ip of a domain daddr if needs_resolve. origin resolves it again from the cache
+pub(crate) async fn resolve_daddr(daddr: &String) -> Option<String> {
+    if !needs_resolve(daddr) {
+        return None;
+    }
+
+    match crate::resolve::resolve(daddr).await {
+        Ok(o) => Some(o),
+        Err(e) => {
+            debug!("resolve_daddr {} {}", daddr, e);
+            None
+        }
+    }
+}
+
+// resolved: daddr is the ip of a domain, only routes with "resolve_daddr" are evaluated
+fn find_route(
+    tag: &str,
+    network: &str,
+    saddr: &str,
+    daddr: &str,
+    inspected: &Inspected,
+    resolved: bool,
+    cache: &mut RouteCache,
+) -> Option<Jump> {
+    let trace = TRACE.load(Ordering::Relaxed);
+    for (index, route_iter) in ROUTE.read().iter().enumerate() {
+        if resolved && !route_iter.resolve_daddr {
             continue;
         }
+        if let Ok(matched) = match_rule(&route_iter.rule, tag, network, saddr, daddr, inspected) {
+            let log = route_iter.log || trace;
 
-        match split_addr_str(saddr.as_str()) {
-            Ok((saddr, sport)) => {
-                if !match_route_addr(&route_iter.saddr, &saddr) {
-                    continue;
+            // exhausted quota jumps to the fallback, or drops
+            let quota = match &route_iter.quota {
+                Some(quota) => match cache.charge(quota, saddr) {
+                    Some(charge) => Some(charge),
+                    None => {
+                        let (fallback_tag, fallback) = &quota.fallback;
+                        route_iter.hits.fetch_add(1, Ordering::Relaxed);
+                        return Some(Jump {
+                            out: fallback.clone(),
+                            tag: fallback_tag.clone(),
+                            index: Some(index),
+                            matched: if log {
+                                Some(format!(
+                                    "{}, quota {} exhausted",
+                                    describe_matched(&matched),
+                                    quota.config.tag
+                                ))
+                            } else {
+                                None
+                            },
+                            quota: None,
+                        });
+                    }
+                },
+                None => None,
+            };
+
+            route_iter.hits.fetch_add(1, Ordering::Relaxed);
+            return Some(Jump {
+                out: route_iter.jump.clone(),
+                tag: route_iter.jump_tag.clone(),
+                index: Some(index),
+                matched: if log {
+                    Some(describe_matched(&matched))
+                } else {
+                    None
+                },
+                quota,
+            });
+        }
+    }
+
+    None
+}
+
+fn default_jump() -> Jump {
+    DEFAULT_HITS.fetch_add(1, Ordering::Relaxed);
+    let (tag, out) = OUT.read()[0].clone();
+    Jump {
+        out,
+        tag,
+        index: None,
+        matched: if TRACE.load(Ordering::Relaxed) {
+            Some("default".to_string())
+        } else {
+            None
+        },
+        quota: None,
+    }
+}
+
+// "daddr by domain, dport by dport", or "catch-all" for a route without fields
+pub(crate) fn describe_matched(matched: &[(&str, &str)]) -> String {
+    if matched.is_empty() {
+        return "catch-all".to_string();
+    }
+
+    matched
+        .iter()
+        .map(|(field, matcher)| format!("{} by {}", field, matcher))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+// (route index or "default", jump, hits)
+pub(crate) fn route_hits() -> Vec<(String, String, u64)> {
+    let mut hits: Vec<(String, String, u64)> = ROUTE
+        .read()
+        .iter()
+        .enumerate()
+        .map(|(index, x)| {
+            (
+                index.to_string(),
+                x.jump_tag.clone(),
+                x.hits.load(Ordering::Relaxed),
+            )
+        })
+        .collect();
+    if let Some((tag, _)) = OUT.read().first() {
+        hits.push((
+            "default".to_string(),
+            tag.clone(),
+            DEFAULT_HITS.load(Ordering::Relaxed),
+        ));
+    }
+
+    hits
+}
+
+// what routes see of a flow besides its addresses
+#[derive(Default)]
+pub(crate) struct Inspected<'a> {
+    // a udp packet, for dns_domain
+    pub(crate) buf: &'a [u8],
+    // the local process of saddr, if any route needs it
+    pub(crate) owner: Option<&'a SocketOwner>,
+    // the first bytes of a tcp flow, or the quic Initial packets of a udp one
+    pub(crate) sniffed: Option<&'a Sniffed>,
+}
+
+// Ok: (field, matcher) of every non-empty field, Err: the first field that missed.
+pub(crate) fn match_rule(
+    rule: &Rule,
+    tag: &str,
+    network: &str,
+    saddr: &str,
+    daddr: &str,
+    inspected: &Inspected,
+) -> Result<Vec<(&'static str, &'static str)>, &'static str> {
+    let mut matched = Vec::new();
+
+    if !rule.tag.is_empty() {
+        if !rule.tag.iter().any(|x| x == tag) {
+            return Err("tag");
+        }
+        matched.push(("tag", "tag"));
+    }
+
+    if !rule.network.is_empty() {
+        if !rule.network.iter().any(|x| x == network) {
+            return Err("network");
+        }
+        matched.push(("network", "network"));
+    }
+
+    match split_addr_str(saddr) {
+        Ok((saddr, sport)) => {
+            if !rule.saddr.is_empty() {
+                matched.push((
+                    "saddr",
+                    match_route_addr(&rule.saddr, &saddr).ok_or("saddr")?,
+                ));
+            }
+
+            if !rule.sport.is_empty() {
+                if !rule.sport.contains(&sport) {
+                    return Err("sport");
                 }
+                matched.push(("sport", "sport"));
+            }
+        }
+        Err(e) => {
+            warn!("split_addr_str {} -> {} {}", saddr, daddr, e);
+        }
+    }
 
-                if route_iter.sport.len() != 0 && !route_iter.sport.contains(&sport) {
-                    continue;
+    match split_addr_str(daddr) {
+        Ok((daddr, dport)) => {
+            if !rule.daddr.is_empty() {
+                matched.push((
+                    "daddr",
+                    match_route_addr(&rule.daddr, &daddr).ok_or("daddr")?,
+                ));
+            }
+
+            if !rule.dport.is_empty() {
+                if !rule.dport.contains(&dport) {
+                    return Err("dport");
                 }
+                matched.push(("dport", "dport"));
             }
-            Err(e) => {
-                warn!("split_addr_str {} -> {} {}", saddr, daddr, e);
+        }
+        Err(e) => {
+            warn!("split_addr_str {} -> {} {}", saddr, daddr, e);
+        }
+    }
+
+    // owner of a local source
+    if !rule.process_name.is_empty()
+        || !rule.process_path.is_empty()
+        || !rule.uid.is_empty()
+        || !rule.gid.is_empty()
+    {
+        let owner = inspected.owner;
+        if !rule.process_name.is_empty() {
+            match owner.and_then(|x| x.name.as_ref()) {
+                Some(name) if rule.process_name.contains(name) => {}
+                _ => return Err("process_name"),
             }
+            matched.push(("process_name", "process_name"));
         }
 
-        match split_addr_str(daddr.as_str()) {
-            Ok((daddr, dport)) => {
-                if !match_route_addr(&route_iter.daddr, &daddr) {
-                    continue;
-                }
+        if !rule.process_path.is_empty() {
+            match owner.and_then(|x| x.path.as_ref()) {
+                Some(path) if rule.process_path.contains(path) => {}
+                _ => return Err("process_path"),
+            }
+            matched.push(("process_path", "process_path"));
+        }
 
-                if route_iter.dport.len() != 0 && !route_iter.dport.contains(&dport) {
-                    continue;
-                }
+        if !rule.uid.is_empty() {
+            match owner.map(|x| x.uid) {
+                Some(uid) if rule.uid.contains(&uid) => {}
+                _ => return Err("uid"),
             }
-            Err(e) => {
-                warn!("split_addr_str {} -> {} {}", saddr, daddr, e);
+            matched.push(("uid", "uid"));
+        }
+
+        if !rule.gid.is_empty() {
+            match owner.and_then(|x| x.gid) {
+                Some(gid) if rule.gid.contains(&gid) => {}
+                _ => return Err("gid"),
             }
+            matched.push(("gid", "gid"));
         }
+    }
 
-        // dns_domain
-        if network.as_str() == "udp" {
-            if let Ok(dns_msg) = Message::from_vec(udp_buf) {
-                if !dns_msg
+    // dns_domain
+    if network == "udp" && !rule.dns_domain.is_empty() {
+        if let Ok(dns_msg) = Message::from_vec(inspected.buf) {
+            matched.push((
+                "dns_domain",
+                dns_msg
                     .queries()
-                    .into_iter()
-                    .any(|x| match_route_addr(&route_iter.dns_domain, &x.name().to_utf8()))
-                {
-                    continue;
-                }
-            };
+                    .iter()
+                    .find_map(|x| match_route_addr(&rule.dns_domain, &x.name().to_utf8()))
+                    .ok_or("dns_domain")?,
+            ));
+        };
+    }
+
+    // sniff_domain and alpn
+    if !rule.sniff_domain.is_empty() || !rule.alpn.is_empty() {
+        if !rule.sniff_domain.is_empty() {
+            matched.push((
+                "sniff_domain",
+                inspected
+                    .sniffed
+                    .and_then(|x| match_route_addr(&rule.sniff_domain, &x.domain))
+                    .ok_or("sniff_domain")?,
+            ));
         }
 
-        return route_iter.jump.clone();
+        if !rule.alpn.is_empty() {
+            match inspected.sniffed {
+                Some(x) if x.alpn.iter().any(|x| rule.alpn.contains(x)) => {}
+                _ => return Err("alpn"),
+            }
+            matched.push(("alpn", "alpn"));
+        }
+    }
+
+    // sub-rules, "any" and "all" add what their rules matched
+    if let Some(not) = &rule.not {
+        if match_rule(not, tag, network, saddr, daddr, inspected).is_ok() {
+            return Err("not");
+        }
+        matched.push(("not", "not"));
+    }
+
+    if !rule.any.is_empty() {
+        matched.extend(
+            rule.any
+                .iter()
+                .find_map(|x| match_rule(x, tag, network, saddr, daddr, inspected).ok())
+                .ok_or("any")?,
+        );
+    }
+
+    for all in &rule.all {
+        matched.extend(match_rule(all, tag, network, saddr, daddr, inspected).map_err(|_| "all")?);
     }
 
-    // default out
-    OUT.read()[0].clone()
+    Ok(matched)
 }
 
+// return the name of the matcher which matched
 #[inline]
-fn match_route_addr(route_addr: &RouteAddr, match_obj: &String) -> bool {
-    route_addr.empty
-        || route_addr.full.is_match(format!(" {} ", match_obj))
-        || route_addr.substring.is_match(match_obj)
-        || {
-            let mut domain_vec: Vec<&str> = match_obj.split('.').collect();
-            domain_vec.retain(|x| !x.is_empty());
-
-            for index in 0..domain_vec.len() {
-                if route_addr
-                    .domain
-                    .is_match(format!(" {} ", domain_vec[index..].join(".")))
-                {
-                    return true;
-                }
-            }
-
-            false
+pub(crate) fn match_route_addr(route_addr: &RouteAddr, match_obj: &String) -> Option<&'static str> {
+    if let Some(negated) = &route_addr.negated {
+        if match_route_addr(negated, match_obj).is_some() {
+            return None;
         }
-        || match match_obj.parse::<IpAddr>() {
-            Ok(ipaddr) => match ipaddr {
-                IpAddr::V4(ip) => route_addr.cidr4.longest_match(ip).is_some(),
-                IpAddr::V6(ip) => route_addr.cidr6.longest_match(ip).is_some(),
-            },
-            Err(_) => false,
+        // only "!" entries
+        if route_addr.empty {
+            return Some("negation");
         }
-        || route_addr.regex.is_match(match_obj.as_str())
+    }
+
+    if route_addr.empty {
+        return Some("empty");
+    }
+
+    if route_addr.full.is_match(format!(" {} ", match_obj)) {
+        return Some("full");
+    }
+
+    if route_addr.substring.is_match(match_obj) {
+        return Some("substring");
+    }
+
+    let mut domain_vec: Vec<&str> = match_obj.split('.').collect();
+    domain_vec.retain(|x| !x.is_empty());
+    for index in 0..domain_vec.len() {
+        if route_addr
+            .domain
+            .is_match(format!(" {} ", domain_vec[index..].join(".")))
+        {
+            return Some("domain");
+        }
+    }
+
+    match match_obj.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) if route_addr.cidr4.longest_match(ip).is_some() => return Some("cidr4"),
+        Ok(IpAddr::V6(ip)) if route_addr.cidr6.longest_match(ip).is_some() => return Some("cidr6"),
+        Ok(ip) if route_addr.geoip.is_match(ip) => return Some("geoip"),
+        _ => {}
+    }
+
+    if route_addr.regex.is_match(match_obj.as_str()) {
+        return Some("regex");
+    }
+
+    None
 }
 
 #[test]
@@ -118,3 +530,88 @@ fn test_domain() {
         println!(" {} ", domain_vec[index..].join("."));
     }
 }
+
+#[test]
+fn test_describe_matched() {
+    assert_eq!(describe_matched(&[]), "catch-all");
+    assert_eq!(
+        describe_matched(&[("daddr", "domain"), ("dport", "dport")]),
+        "daddr by domain, dport by dport"
+    );
+}
+
+#[test]
+fn test_match_rule() {
+    let config: super::RuleConfig = serde_json::from_value(serde_json::json!({
+        "daddr": ["domain a.com", "!full b.a.com"],
+        "not": { "dport": [80] },
+        "any": [{ "network": ["udp"] }, { "dport": [443] }]
+    }))
+    .unwrap();
+    let mut errors = Vec::new();
+    let rule = super::parse_rule(&config, "route[0]", None, false, &mut errors);
+    assert!(errors.is_empty());
+
+    let match_daddr = |network: &str, daddr: &str| {
+        match_rule(
+            &rule,
+            "in",
+            network,
+            "1.1.1.1:1",
+            daddr,
+            &Inspected::default(),
+        )
+    };
+    assert_eq!(
+        describe_matched(&match_daddr("tcp", "x.a.com:443").unwrap()),
+        "daddr by domain, not by not, dport by dport"
+    );
+    assert!(match_daddr("udp", "a.com:53").is_ok());
+    assert_eq!(match_daddr("tcp", "b.a.com:443"), Err("daddr"));
+    assert_eq!(match_daddr("udp", "a.com:80"), Err("not"));
+    assert_eq!(match_daddr("tcp", "a.com:22"), Err("any"));
+}
+
+#[tokio::test]
+async fn test_quota_exhausted() {
+    use super::{Quota, RouteConfig};
+
+    let config: RouteConfig = serde_json::from_value(serde_json::json!({
+        "tag": ["test_quota_exhausted"],
+        "jump": "origin",
+        "quota": { "tag": "test_quota_exhausted", "limit": 10 }
+    }))
+    .unwrap();
+    let mut errors = Vec::new();
+    ROUTE.write().push(Route {
+        rule: super::parse_rule(&config.rule(), "route[0]", None, false, &mut errors),
+        jump: crate::origin::Out::new(&serde_json::from_str(r#"{"tag": "origin"}"#).unwrap()),
+        jump_tag: "origin".to_string(),
+        hits: AtomicU64::new(0),
+        log: false,
+        quota: Some(Quota::new(config.quota.as_ref().unwrap(), &HashMap::new())),
+        resolve_daddr: false,
+    });
+
+    async fn find(cache: &mut RouteCache) -> Jump {
+        find_out_resolved(
+            "test_quota_exhausted".to_string(),
+            "udp".to_string(),
+            "10.0.0.2:5000".to_string(),
+            "1.1.1.1:53".to_string(),
+            &[],
+            None,
+            cache,
+        )
+        .await
+    }
+
+    // a udp association charges once and sees it exhausted, without fallback it drops
+    let mut cache = RouteCache::default();
+    let jump = find(&mut cache).await;
+    assert_eq!(jump.tag, "origin");
+    assert!(!jump.quota.unwrap().add(10));
+    let jump = find(&mut cache).await;
+    assert_eq!((jump.tag.as_str(), jump.quota.is_none()), ("drop", true));
+    assert_eq!(find(&mut RouteCache::default()).await.tag, "drop");
+}
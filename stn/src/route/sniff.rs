@@ -0,0 +1,412 @@
+use super::{is_quic_initial, sniff_quic};
+use crate::{config::deserialize_secs, misc::split_addr_str};
+use parking_lot::RwLock;
+use serde::Deserialize;
+use std::{net::IpAddr, sync::Arc, time::Duration};
+use tokio::{
+    sync::mpsc::Receiver,
+    time::{timeout_at, Instant},
+};
+
+lazy_static::lazy_static! {
+    static ref SNIFF: RwLock<Option<Arc<SniffConfig>>> = RwLock::new(None);
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SniffConfig {
+    // ins whose tcp flows to an ip are sniffed, empty for all
+    #[serde(default)]
+    pub(crate) tag: Vec<String>,
+    // empty for all
+    #[serde(default)]
+    pub(crate) dport: Vec<usize>,
+    // bytes of the client buffered at most
+    #[serde(default = "default_sniff_bytes")]
+    pub(crate) bytes: usize,
+    // a server which speaks first waits this long
+    #[serde(
+        default = "default_sniff_timeout",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub(crate) timeout: Duration,
+    // the out connects to the sniffed domain instead of the ip
+    #[serde(default)]
+    pub(crate) override_daddr: bool,
+}
+
+fn default_sniff_bytes() -> usize {
+    8192
+}
+
+fn default_sniff_timeout() -> Duration {
+    Duration::from_millis(300)
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct Sniffed {
+    // "tls", "http" or "quic"
+    pub(crate) protocol: &'static str,
+    pub(crate) domain: String,
+    pub(crate) alpn: Vec<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Sniff {
+    Found(Sniffed),
+    // may still be a ClientHello or a request
+    Incomplete,
+    Unknown,
+}
+
+// setting.sniff, needs a restart like other settings
+pub(crate) fn init_sniff(config: &Option<SniffConfig>) {
+    *SNIFF.write() = config.clone().map(Arc::new);
+}
+
+// setting.sniff if it applies to a flow, only an ip daddr is sniffed
+pub(crate) fn sniff_config(tag: &str, daddr: &str) -> Option<Arc<SniffConfig>> {
+    let config = SNIFF.read().clone()?;
+    if !config.tag.is_empty() && !config.tag.iter().any(|x| x == tag) {
+        return None;
+    }
+
+    match split_addr_str(daddr) {
+        Ok((ip, dport))
+            if ip.parse::<IpAddr>().is_ok()
+                && (config.dport.is_empty() || config.dport.contains(&dport)) =>
+        {
+            Some(config)
+        }
+        _ => None,
+    }
+}
+
+// first bytes of the client until they are sniffed, or bytes or timeout is reached.
+// None if the client closed without sending anything
+pub(crate) async fn read_first(
+    rx: &mut Receiver<Vec<u8>>,
+    config: &SniffConfig,
+) -> Option<Vec<u8>> {
+    let deadline = Instant::now() + config.timeout;
+    let mut buf = Vec::new();
+
+    loop {
+        match timeout_at(deadline, rx.recv()).await {
+            Ok(Some(data)) => buf.extend_from_slice(&data),
+            Ok(None) if buf.is_empty() => return None,
+            _ => return Some(buf),
+        }
+        if buf.len() >= config.bytes || sniff_tcp(&buf) != Sniff::Incomplete {
+            return Some(buf);
+        }
+    }
+}
+
+// the first bytes of a tcp flow, or the quic Initial packets of a udp flow
+pub(crate) fn sniff(network: &str, buf: &[u8]) -> Option<Sniffed> {
+    let sniff = match network {
+        "tcp" => sniff_tcp(buf),
+        "udp" if is_quic_initial(buf) => sniff_quic(buf),
+        _ => return None,
+    };
+
+    match sniff {
+        Sniff::Found(s) => Some(s),
+        _ => None,
+    }
+}
+
+pub(crate) fn sniff_tcp(buf: &[u8]) -> Sniff {
+    match buf.first() {
+        None => Sniff::Incomplete,
+        Some(0x16) => sniff_tls(buf),
+        Some(_) => sniff_http(buf),
+    }
+}
+
+// handshake records, a ClientHello may span several
+fn sniff_tls(buf: &[u8]) -> Sniff {
+    let mut handshake = Vec::new();
+    let mut rest = buf;
+    while rest.len() >= 5 {
+        // change_cipher_spec or 0-RTT data may follow a ClientHello in the same read
+        if rest[0] != 0x16 {
+            return match parse_client_hello(&handshake) {
+                Sniff::Incomplete => Sniff::Unknown,
+                sniff => sniff,
+            };
+        }
+        // content type 22, version 3.x
+        if rest[1] != 3 {
+            return Sniff::Unknown;
+        }
+        let len = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+        let end = (5 + len).min(rest.len());
+        handshake.extend_from_slice(&rest[5..end]);
+        rest = &rest[end..];
+    }
+
+    parse_client_hello(&handshake)
+}
+
+// a handshake message, from tls records or quic crypto frames
+pub(crate) fn parse_client_hello(handshake: &[u8]) -> Sniff {
+    if handshake.len() < 4 {
+        return Sniff::Incomplete;
+    }
+    // client_hello
+    if handshake[0] != 1 {
+        return Sniff::Unknown;
+    }
+    let len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+    match handshake.get(4..4 + len) {
+        Some(body) => parse_client_hello_body(body).unwrap_or(Sniff::Unknown),
+        None => Sniff::Incomplete,
+    }
+}
+
+fn parse_client_hello_body(body: &[u8]) -> Option<Sniff> {
+    let mut body = ByteReader(body);
+    // legacy_version, random
+    body.take(2 + 32)?;
+    let n = body.u8()? as usize;
+    body.take(n)?;
+    let n = body.u16()? as usize;
+    body.take(n)?;
+    let n = body.u8()? as usize;
+    body.take(n)?;
+    let n = body.u16()? as usize;
+    let mut extensions = ByteReader(body.take(n)?);
+
+    let mut domain = None;
+    let mut alpn = Vec::new();
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let n = extensions.u16()? as usize;
+        let mut data = ByteReader(extensions.take(n)?);
+        match kind {
+            // server_name
+            0 => {
+                let n = data.u16()? as usize;
+                let mut list = ByteReader(data.take(n)?);
+                while !list.0.is_empty() {
+                    let name_type = list.u8()?;
+                    let n = list.u16()? as usize;
+                    let name = list.take(n)?;
+                    // host_name
+                    if name_type == 0 {
+                        domain = Some(std::str::from_utf8(name).ok()?.to_string());
+                    }
+                }
+            }
+            // application_layer_protocol_negotiation
+            16 => {
+                let n = data.u16()? as usize;
+                let mut list = ByteReader(data.take(n)?);
+                while !list.0.is_empty() {
+                    let n = list.u8()? as usize;
+                    alpn.push(String::from_utf8_lossy(list.take(n)?).to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Some(match domain.and_then(|x| normalize_domain(&x)) {
+        Some(domain) => Sniff::Found(Sniffed {
+            protocol: "tls",
+            domain,
+            alpn,
+        }),
+        None => Sniff::Unknown,
+    })
+}
+
+const METHODS: [&[u8]; 9] = [
+    b"GET ",
+    b"POST ",
+    b"HEAD ",
+    b"PUT ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"CONNECT ",
+    b"TRACE ",
+];
+
+// "GET / HTTP/1.1\r\nHost: a.com:8080\r\n\r\n" -> a.com
+fn sniff_http(buf: &[u8]) -> Sniff {
+    if !METHODS
+        .iter()
+        .any(|x| buf.starts_with(x) || x.starts_with(buf))
+    {
+        return Sniff::Unknown;
+    }
+    let end = match buf.windows(4).position(|x| x == b"\r\n\r\n") {
+        Some(s) => s,
+        None => return Sniff::Incomplete,
+    };
+    let head = match std::str::from_utf8(&buf[..end]) {
+        Ok(o) => o,
+        Err(_) => return Sniff::Unknown,
+    };
+
+    let mut lines = head.split("\r\n");
+    match lines.next() {
+        Some(s) if s.ends_with(" HTTP/1.1") || s.ends_with(" HTTP/1.0") => {}
+        _ => return Sniff::Unknown,
+    }
+    let host = lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.eq_ignore_ascii_case("host") {
+            Some(value.trim())
+        } else {
+            None
+        }
+    });
+    // without the port
+    let host = host.map(|x| match x.rfind(':') {
+        Some(index) if !x.ends_with(']') => &x[..index],
+        _ => x,
+    });
+
+    match host.and_then(normalize_domain) {
+        Some(domain) => Sniff::Found(Sniffed {
+            protocol: "http",
+            domain,
+            alpn: Vec::new(),
+        }),
+        None => Sniff::Unknown,
+    }
+}
+
+// lowercase without the trailing dot, ips are not domains
+fn normalize_domain(domain: &str) -> Option<String> {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    if domain.is_empty()
+        || domain.parse::<IpAddr>().is_ok()
+        || domain.starts_with('[')
+        || !domain
+            .bytes()
+            .all(|x| x.is_ascii_alphanumeric() || x == b'-' || x == b'.' || x == b'_')
+    {
+        return None;
+    }
+
+    Some(domain)
+}
+
+// big endian fields of tls and quic
+pub(crate) struct ByteReader<'a>(pub(crate) &'a [u8]);
+
+impl<'a> ByteReader<'a> {
+    pub(crate) fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    pub(crate) fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|x| x[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|x| u16::from_be_bytes([x[0], x[1]]))
+    }
+
+    // quic variable-length integer, the first 2 bits are log2 of its length
+    pub(crate) fn varint(&mut self) -> Option<u64> {
+        let first = *self.0.first()?;
+        let bytes = self.take(1 << (first >> 6))?;
+        Some(
+            bytes[1..]
+                .iter()
+                .fold((first & 0x3f) as u64, |value, x| value << 8 | *x as u64),
+        )
+    }
+}
+
+// a minimal ClientHello handshake message, for stn route --sni and tests
+pub(crate) fn build_client_hello(domain: &str, alpn: &[&str]) -> Vec<u8> {
+    let vec16 = |data: &[u8]| -> Vec<u8> {
+        let mut buf = (data.len() as u16).to_be_bytes().to_vec();
+        buf.extend_from_slice(data);
+        buf
+    };
+
+    let mut server_name = vec![0];
+    server_name.extend(vec16(domain.as_bytes()));
+    let mut protocols = Vec::new();
+    for x in alpn {
+        protocols.push(x.len() as u8);
+        protocols.extend_from_slice(x.as_bytes());
+    }
+    let mut extensions = vec![0, 0];
+    extensions.extend(vec16(&vec16(&server_name)));
+    if !alpn.is_empty() {
+        extensions.extend_from_slice(&[0, 16]);
+        extensions.extend(vec16(&vec16(&protocols)));
+    }
+
+    // legacy_version 1.2, random, empty session id, TLS_AES_128_GCM_SHA256, null compression
+    let mut body = vec![3, 3];
+    body.extend_from_slice(&[0; 32]);
+    body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+    body.extend(vec16(&extensions));
+
+    let mut handshake = vec![1];
+    handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    handshake.extend(body);
+    handshake
+}
+
+// the handshake message in a tls record
+pub(crate) fn build_tls_record(handshake: &[u8]) -> Vec<u8> {
+    let mut record = vec![0x16, 3, 1];
+    record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+    record.extend_from_slice(handshake);
+    record
+}
+
+#[test]
+fn test_sniff_tcp() {
+    let record = build_tls_record(&build_client_hello("WWW.A.com.", &["h2", "http/1.1"]));
+    let sniffed = Sniffed {
+        protocol: "tls",
+        domain: "www.a.com".to_string(),
+        alpn: vec!["h2".to_string(), "http/1.1".to_string()],
+    };
+    assert_eq!(sniff_tcp(&record[..3]), Sniff::Incomplete);
+    assert_eq!(sniff_tcp(&record[..record.len() - 1]), Sniff::Incomplete);
+    assert_eq!(sniff_tcp(&record), Sniff::Found(sniffed));
+
+    // split into two records
+    let handshake = build_client_hello("a.com", &[]);
+    let mut split = build_tls_record(&handshake[..10]);
+    split.extend(build_tls_record(&handshake[10..]));
+    assert!(matches!(sniff_tcp(&split), Sniff::Found(x) if x.domain == "a.com"));
+
+    // change_cipher_spec and early data after the ClientHello
+    let mut early = build_tls_record(&handshake);
+    early.extend([0x14, 3, 3, 0, 1, 1, 0x17, 3, 3, 0, 2, 0xaa, 0xbb]);
+    assert!(matches!(sniff_tcp(&early), Sniff::Found(x) if x.domain == "a.com"));
+    let mut early = build_tls_record(&handshake[..10]);
+    early.extend([0x14, 3, 3, 0, 1, 1]);
+    assert_eq!(sniff_tcp(&early), Sniff::Unknown);
+
+    assert_eq!(sniff_tcp(b"GE"), Sniff::Incomplete);
+    assert_eq!(sniff_tcp(b"GET / HTTP/1.1\r\nHost: a"), Sniff::Incomplete);
+    assert!(matches!(
+        sniff_tcp(b"GET / HTTP/1.1\r\nhost: a.com:8080\r\n\r\n"),
+        Sniff::Found(x) if x.domain == "a.com" && x.protocol == "http"
+    ));
+    assert_eq!(
+        sniff_tcp(b"GET / HTTP/1.1\r\nHost: [::1]:80\r\n\r\n"),
+        Sniff::Unknown
+    );
+    assert_eq!(sniff_tcp(b"SSH-2.0-OpenSSH\r\n"), Sniff::Unknown);
+}
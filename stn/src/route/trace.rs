@@ -0,0 +1,156 @@
+use super::{
+    build_client_hello, build_quic_initial, build_tls_record, describe_matched, match_rule,
+    resolve_daddr, route_out_parse, sniff, socket_owner, Inspected, ROUTE,
+};
+use crate::config;
+use std::str::FromStr;
+use trust_dns_proto::{
+    op::{Message, MessageType, Query},
+    rr::{DNSClass, Name, RecordType},
+};
+
+// stn route -c [file] --tag [tag] --daddr [addr] ...
+// print every route evaluated and the out the flow would take
+pub(crate) fn route_trace(
+    args: &[String],
+    format: Option<config::Format>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config_path = None;
+    let mut tag = None;
+    let mut network = "tcp".to_string();
+    let mut saddr = "0.0.0.0:0".to_string();
+    let mut daddr = None;
+    let mut buf = Vec::new();
+    let mut sni = None;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let value = iter
+            .next()
+            .ok_or_else(|| format!("{} needs a value", arg))?
+            .clone();
+        match arg.as_str() {
+            "-c" => config_path = Some(value),
+            "--tag" => tag = Some(value),
+            "--network" => network = value,
+            "--saddr" => saddr = value,
+            "--daddr" => daddr = Some(value),
+            "--dns" => buf = build_dns_query(&value)?,
+            "--dns-hex" => buf = decode_hex(&value)?,
+            "--sni" => sni = Some(value),
+            "--host" => buf = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", value).into_bytes(),
+            _ => Err(format!("unknown option {}", arg))?,
+        }
+    }
+    let config_path = config_path.ok_or("-c not found")?;
+    let tag = tag.ok_or("--tag not found")?;
+    let mut daddr = daddr.ok_or("--daddr not found")?;
+    // a tls ClientHello for tcp, a quic Initial packet for udp
+    if let Some(sni) = sni {
+        let client_hello = build_client_hello(&sni, &[]);
+        buf = match network.as_str() {
+            "udp" => build_quic_initial(&[0x5a; 8], 0, &client_hello),
+            _ => build_tls_record(&client_hello),
+        };
+    }
+
+    let config = config::load(&config_path, format)?;
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async { route_out_parse(&config) })?;
+    let owner = runtime.block_on(socket_owner(&network, &saddr, true));
+
+    println!("{} {} {} -> {}", tag, network, saddr, daddr);
+    if let Ok(dns_msg) = Message::from_vec(&buf) {
+        for query in dns_msg.queries() {
+            println!("dns_domain {}", query.name().to_utf8());
+        }
+    }
+    let sniffed = sniff(&network, &buf);
+    if let Some(sniffed) = &sniffed {
+        println!("sniffed {} {}", sniffed.protocol, sniffed.domain);
+        if network == "tcp" && matches!(&config.setting.sniff, Some(x) if x.override_daddr) {
+            let dport = daddr.rsplit(':').next().unwrap_or_default();
+            daddr = format!("{}:{}", sniffed.domain, dport);
+            println!("daddr sniffed as {}", daddr);
+        }
+    }
+
+    let inspected = Inspected {
+        buf: &buf,
+        owner: owner.as_deref(),
+        sniffed: sniffed.as_ref(),
+    };
+    for (index, route) in ROUTE.read().iter().enumerate() {
+        match match_rule(&route.rule, &tag, &network, &saddr, &daddr, &inspected) {
+            Ok(matched) => {
+                println!("route[{}] hit: {}", index, describe_matched(&matched));
+                println!("out: {}", route.jump_tag);
+                return Ok(());
+            }
+            Err(field) => println!("route[{}] miss: {}", index, field),
+        }
+    }
+
+    // routes with resolve_daddr again
+    let resolved = runtime.block_on(async {
+        crate::resolve::init_resolve(&config.resolve);
+        resolve_daddr(&daddr).await
+    });
+    if let Some(resolved) = resolved {
+        println!("daddr resolved to {}", resolved);
+        for (index, route) in ROUTE.read().iter().enumerate() {
+            if !route.resolve_daddr {
+                continue;
+            }
+            match match_rule(&route.rule, &tag, &network, &saddr, &resolved, &inspected) {
+                Ok(matched) => {
+                    println!("route[{}] hit: {}", index, describe_matched(&matched));
+                    println!("out: {}", route.jump_tag);
+                    return Ok(());
+                }
+                Err(field) => println!("route[{}] miss: {}", index, field),
+            }
+        }
+    }
+
+    println!("out: {} (default)", config.out[0].tag());
+
+    Ok(())
+}
+
+fn build_dns_query(domain: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut dns_msg = Message::new();
+    let mut query = Query::new();
+    query.set_name(Name::from_str(domain)?);
+    query.set_query_class(DNSClass::IN);
+    query.set_query_type(RecordType::A);
+    dns_msg.add_query(query);
+    dns_msg.set_message_type(MessageType::Query);
+    dns_msg.set_recursion_desired(true);
+
+    Ok(dns_msg.to_vec()?)
+}
+
+// "0a1b" -> [0x0a, 0x1b]
+fn decode_hex(hex: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let hex: Vec<u8> = hex.bytes().filter(|x| !x.is_ascii_whitespace()).collect();
+    if !hex.len().is_multiple_of(2) {
+        Err("invalid hex length")?
+    }
+
+    let mut buf = Vec::with_capacity(hex.len() / 2);
+    for pair in hex.chunks(2) {
+        buf.push(u8::from_str_radix(std::str::from_utf8(pair)?, 16)?);
+    }
+
+    Ok(buf)
+}
+
+#[test]
+fn test_decode_hex() {
+    assert_eq!(decode_hex("0a1B ff").unwrap(), vec![0x0a, 0x1b, 0xff]);
+    assert!(decode_hex("0a1").is_err());
+    assert!(decode_hex("zz").is_err());
+}
@@ -0,0 +1,90 @@
+use log::*;
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use tokio::{sync::watch, time::Instant};
+
+lazy_static::lazy_static! {
+    static ref SHUTDOWN: (watch::Sender<bool>, watch::Receiver<bool>) = watch::channel(false);
+}
+
+// running bidirectional_with_timeout! relays
+static RELAYS: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) struct RelayGuard;
+
+impl RelayGuard {
+    pub(crate) fn new() -> Self {
+        RELAYS.fetch_add(1, Ordering::Relaxed);
+        RelayGuard
+    }
+}
+
+impl Drop for RelayGuard {
+    fn drop(&mut self) {
+        RELAYS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// return when shutdown begins, listeners select on it
+pub(crate) async fn wait_shutdown() {
+    let mut shutdown_rx = SHUTDOWN.1.clone();
+    while !*shutdown_rx.borrow() {
+        if shutdown_rx.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+// udp listeners keep receiving for known sources
+pub(crate) fn is_shutdown() -> bool {
+    *SHUTDOWN.1.borrow()
+}
+
+// SIGTERM or SIGINT
+pub(crate) async fn wait_signal() {
+    cfg_if::cfg_if! {
+        if #[cfg(not(target_os = "windows"))] {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let mut terminate = match signal(SignalKind::terminate()) {
+                Ok(o) => o,
+                Err(e) => {
+                    warn!("{}", e);
+                    let _ = tokio::signal::ctrl_c().await;
+                    return;
+                }
+            };
+            tokio::select! {
+                _ = terminate.recv() => info!("SIGTERM received"),
+                _ = tokio::signal::ctrl_c() => info!("SIGINT received"),
+            }
+        } else {
+            let _ = tokio::signal::ctrl_c().await;
+            info!("ctrl-c received");
+        }
+    }
+}
+
+// stop all listeners, then wait for relays until drain_timeout
+pub(crate) async fn shutdown(drain_timeout: Duration) {
+    #[cfg(not(target_os = "windows"))]
+    crate::systemd::notify("STOPPING=1");
+
+    let _ = SHUTDOWN.0.send(true);
+    info!(
+        "stop accepting, drain {} relays",
+        RELAYS.load(Ordering::Relaxed)
+    );
+
+    let deadline = Instant::now() + drain_timeout;
+    while RELAYS.load(Ordering::Relaxed) != 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let relays = RELAYS.load(Ordering::Relaxed);
+    if relays != 0 {
+        warn!("drain timeout, {} relays aborted", relays);
+    }
+}
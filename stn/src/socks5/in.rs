@@ -1,8 +1,13 @@
 use super::*;
-use crate::misc::{build_socket_listener, socketaddr_to_string};
+use crate::route::in_span;
+use crate::{
+    config::*,
+    misc::{build_socket_listener, socketaddr_to_string},
+};
 use bytes::BufMut;
 use log::*;
-use std::{sync::Arc, time::Duration};
+use serde::Deserialize;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use stn_buf::VecBuf;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
@@ -10,66 +15,90 @@ use tokio::{
     sync::mpsc::Sender,
     time::timeout,
 };
+use tracing::Instrument;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct InConfig {
+    pub(crate) tag: String,
+    pub(crate) address: SocketAddr,
+    #[serde(default = "default_true")]
+    pub(crate) tcp_nodelay: bool,
+    #[serde(
+        default = "default_tcp_keepalive_interval",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub(crate) tcp_keepalive_interval: Duration,
+    #[serde(default = "default_tcp_timeout", deserialize_with = "deserialize_secs")]
+    pub(crate) tcp_timeout: Duration,
+    #[serde(default = "default_udp_timeout", deserialize_with = "deserialize_secs")]
+    pub(crate) udp_timeout: Duration,
+}
 
 pub(crate) struct In {
     pub(crate) tag: String,
     pub(crate) tcp_nodelay: bool,
-    pub(crate) tcp_keepalive_inverval: Duration,
+    pub(crate) tcp_keepalive_interval: Duration,
     pub(crate) tcp_timeout: Duration,
     pub(crate) udp_timeout: Duration,
-    pub(crate) tcp_listener: TcpListener,
     pub(crate) udp_listener: UdpSocket,
     pub(crate) fullcone_map: dashmap::DashMap<String, Sender<Vec<u8>>>,
 }
 
 impl In {
-    pub(crate) async fn start(root: serde_json::Value) {
-        let bind_addr = root["address"].as_str().expect("address not found");
+    pub(crate) async fn start(config: InConfig) {
+        let bind_addr = config.address.to_string();
+        let tag = Some(config.tag.as_str());
+        // owned by the accept loop, so it closes when accepting stops
+        let tcp_listener = TcpListener::from_std(
+            build_socket_listener("tcp", &bind_addr, tag)
+                .unwrap()
+                .into(),
+        )
+        .unwrap();
 
         let r#in = Arc::new(In {
-            tag: root["tag"].as_str().expect("tag not found").to_string(),
-            tcp_nodelay: root["tcp_nodelay"].as_bool().unwrap_or_else(|| true),
-            tcp_keepalive_inverval: Duration::from_nanos(
-                (root["tcp_keepalive_inverval"]
-                    .as_f64()
-                    .unwrap_or_else(|| 30f64)
-                    * 1000_000_000f64) as u64,
-            ),
-            tcp_timeout: Duration::from_nanos(
-                (root["tcp_timeout"].as_f64().unwrap_or_else(|| 300f64) * 1000_000_000f64) as u64,
-            ),
-            udp_timeout: Duration::from_nanos(
-                (root["udp_timeout"].as_f64().unwrap_or_else(|| 60f64) * 1000_000_000f64) as u64,
-            ),
-            tcp_listener: TcpListener::from_std(
-                build_socket_listener("tcp", bind_addr).unwrap().into(),
-            )
-            .unwrap(),
+            tag: config.tag.clone(),
+            tcp_nodelay: config.tcp_nodelay,
+            tcp_keepalive_interval: config.tcp_keepalive_interval,
+            tcp_timeout: config.tcp_timeout,
+            udp_timeout: config.udp_timeout,
             udp_listener: UdpSocket::from_std(
-                build_socket_listener("udp", bind_addr).unwrap().into(),
+                build_socket_listener("udp", &bind_addr, tag)
+                    .unwrap()
+                    .into(),
             )
             .unwrap(),
             fullcone_map: dashmap::DashMap::new(),
         });
 
-        tokio::spawn(r#in.clone().listen());
+        tokio::spawn(r#in.clone().listen(tcp_listener));
         tokio::spawn(r#in.clone().udp_start());
     }
 
-    async fn listen(self: Arc<Self>) {
+    async fn listen(self: Arc<Self>, tcp_listener: TcpListener) {
         loop {
-            let (client, saddr) = match self.tcp_listener.accept().await {
+            let accept = tokio::select! {
+                r = tcp_listener.accept() => r,
+                // stop accepting new flows
+                _ = crate::shutdown::wait_shutdown() => return,
+            };
+            let (client, saddr) = match accept {
                 Ok(o) => o,
                 Err(e) => {
                     warn!("{}", e);
                     continue;
                 }
             };
+            crate::metrics::inc(
+                "stn_in_accepted_total",
+                &[("in", &self.tag), ("network", "tcp")],
+            );
 
             if let Err(e) = crate::misc::set_nodelay_keepalive_interval(
                 &client,
                 self.tcp_nodelay,
-                self.tcp_keepalive_inverval,
+                self.tcp_keepalive_interval,
             ) {
                 warn!("{} {} {}", self.tag, saddr, e);
                 continue;
@@ -87,6 +116,7 @@ impl In {
                         warn!("{} {} {}", self_clone.tag, saddr, e);
                     }
                 }
+                .instrument(in_span(&self.tag, &socketaddr_to_string(&saddr)))
             });
         }
     }
@@ -201,6 +231,32 @@ impl In {
             ))?
         }
 
+        // read CMD
+        match buf[1] {
+            // replied once the out is connected
+            CMD_CONNECT => {
+                if let Err(e) = self.clone().handle_tcp(client, saddr.clone(), buf).await {
+                    warn!("{} {} {}", self.tag, saddr, e);
+                }
+            }
+            CMD_UDP_ASSOCIATE => {
+                self.reply(&mut client, REP_SUCCEEDED).await?;
+                self.handle_udp(client).await
+            }
+            _ => {
+                self.reply(&mut client, REP_COMMAND_NOT_SUPPORTED).await?;
+                Err(format!("{} {} unsupport CMD:{}", self.tag, saddr, buf[1]))?
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) async fn reply(
+        &self,
+        client: &mut TcpStream,
+        rep: u8,
+    ) -> Result<(), Box<dyn std::error::Error>> {
         // +----+-----+-------+------+----------+----------+
         // |VER | REP |  RSV  | ATYP | BND.ADDR | BND.PORT |
         // +----+-----+-------+------+----------+----------+
@@ -229,13 +285,13 @@ impl In {
         // send
         let write_buf = match socketaddr_to_string(&client.local_addr()?).parse()? {
             std::net::SocketAddr::V4(addr) => {
-                let mut buf = vec![5, 0, 0, ATYP_IPV4];
+                let mut buf = vec![5, rep, 0, ATYP_IPV4];
                 buf.extend(addr.ip().octets());
                 buf.put_u16(addr.port());
                 buf
             }
             std::net::SocketAddr::V6(addr) => {
-                let mut buf = vec![5, 0, 0, ATYP_IPV6];
+                let mut buf = vec![5, rep, 0, ATYP_IPV6];
                 buf.extend(addr.ip().octets());
                 buf.put_u16(addr.port());
                 buf
@@ -243,17 +299,6 @@ impl In {
         };
         timeout(self.tcp_timeout, client.write_all(&write_buf)).await??;
 
-        // read CMD
-        match buf[1] {
-            CMD_CONNECT => {
-                if let Err(e) = self.clone().handle_tcp(client, saddr.clone(), buf).await {
-                    warn!("{} {} {}", self.tag, saddr, e);
-                }
-            }
-            CMD_UDP_ASSOCIATE => self.handle_udp(client).await,
-            _ => Err(format!("{} {} unsupport CMD:{}", self.tag, saddr, buf[1]))?,
-        }
-
         Ok(())
     }
 }
@@ -11,7 +11,7 @@ use tokio::{
 impl super::In {
     pub(crate) async fn handle_tcp(
         self: Arc<Self>,
-        client: TcpStream,
+        mut client: TcpStream,
         saddr: String,
         mut buf: Vec<u8>,
     ) -> Result<(), Box<dyn std::error::Error>> {
@@ -38,19 +38,30 @@ impl super::In {
         let (daddr, daddr_len) = get_daddr(&buf[3..])?;
         buf.drain(..4 + daddr_len + 2);
 
-        // connect
-        let (mut client_rx, mut client_tx) = client.into_split();
-        let (server_tx, mut server_rx) = timeout(
+        // connect, then tell the client whether it succeeded
+        let connected = match timeout(
             self.tcp_timeout,
             crate::route::tcp_connect(self.tag.clone(), saddr.clone(), daddr.clone()),
         )
-        .await??;
+        .await
+        {
+            Ok(o) => o.map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        let rep = if connected.is_ok() {
+            REP_SUCCEEDED
+        } else {
+            REP_GENERAL_FAILURE
+        };
+        self.reply(&mut client, rep).await?;
+        let (server_tx, mut server_rx) = connected?;
+        let (mut client_rx, mut client_tx) = client.into_split();
 
-        tokio::spawn(async move {
+        crate::route::spawn_in_span(async move {
             match bidirectional_with_timeout!(
                 {
                     // write server, buf.len() may not 0, so write first
-                    if buf.len() != 0 {
+                    if !buf.is_empty() {
                         debug!("{} {} -> {} {}", self.tag, saddr, daddr, buf.len());
                         server_tx.send(buf.to_vec()).await.or(Err("close"))?;
                         buf.drain(..);
@@ -76,6 +87,7 @@ impl super::In {
                 // client or timeout error
                 (Err(e), _, _) | (_, _, Err(e)) => {
                     let e = e.to_string();
+                    crate::route::report_close(&self.tag, &saddr, &e);
                     if e.as_str() == "close" {
                         debug!("{} {} -> {} {}", self.tag, saddr, daddr, e)
                     } else {
@@ -85,6 +97,7 @@ impl super::In {
                 // server error
                 (_, Err(e), _) => {
                     let e = e.to_string();
+                    crate::route::report_close(&self.tag, &saddr, &e);
                     if e.as_str() == "close" {
                         debug!("{} {} -> {} {}", self.tag, daddr, saddr, e)
                     } else {
@@ -1,5 +1,18 @@
+use crate::config::*;
+use serde::Deserialize;
 use std::{sync::Arc, time::Duration};
 
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct OutConfig {
+    pub(crate) tag: String,
+    pub(crate) address: String,
+    #[serde(default = "default_tcp_timeout", deserialize_with = "deserialize_secs")]
+    pub(crate) tcp_timeout: Duration,
+    #[serde(default = "default_udp_timeout", deserialize_with = "deserialize_secs")]
+    pub(crate) udp_timeout: Duration,
+}
+
 pub(crate) struct Out {
     pub(crate) tag: String,
     pub(crate) addr: String,
@@ -8,19 +21,12 @@ pub(crate) struct Out {
 }
 
 impl Out {
-    pub(crate) fn new(root: &serde_json::Value) -> Arc<dyn crate::route::Out + Send + Sync> {
+    pub(crate) fn new(config: &OutConfig) -> Arc<dyn crate::route::Out + Send + Sync> {
         Arc::new(Self {
-            tag: root["tag"].as_str().expect("tag not found").to_string(),
-            addr: root["address"]
-                .as_str()
-                .expect("address not found")
-                .to_string(),
-            tcp_timeout: Duration::from_nanos(
-                (root["tcp_timeout"].as_f64().unwrap_or_else(|| 300f64) * 1000_000_000f64) as u64,
-            ),
-            udp_timeout: Duration::from_nanos(
-                (root["udp_timeout"].as_f64().unwrap_or_else(|| 60f64) * 1000_000_000f64) as u64,
-            ),
+            tag: config.tag.clone(),
+            addr: config.address.clone(),
+            tcp_timeout: config.tcp_timeout,
+            udp_timeout: config.udp_timeout,
         })
     }
 }
@@ -146,14 +146,14 @@ impl crate::route::OutTcp for super::Out {
             } + 2,
         );
 
-        tokio::spawn(async move {
+        crate::route::spawn_in_span(async move {
             let mut buf = vec![0; TCP_LEN];
             match bidirectional_with_timeout!(
                 {
                     // write server, buf.len() may not 0, write server first
                     debug!("{} {} -> {} {}", self.tag, saddr, daddr, buf.len());
                     server_tx.send(buf.clone()).await.or(Err("close"))?;
-                    buf.resize(0, 0);
+                    buf.clear();
 
                     // read client
                     let recv_data = client_rx.recv().await.ok_or("close")?;
@@ -172,6 +172,7 @@ impl crate::route::OutTcp for super::Out {
                 // client or timeout error
                 (Err(e), _, _) | (_, _, Err(e)) => {
                     let e = e.to_string();
+                    crate::route::report_out_close(&saddr, &e);
                     if e.as_str() == "close" {
                         debug!("{} {} -> {} {}", self.tag, saddr, daddr, e)
                     } else {
@@ -181,6 +182,7 @@ impl crate::route::OutTcp for super::Out {
                 // server error
                 (_, Err(e), _) => {
                     let e = e.to_string();
+                    crate::route::report_out_close(&saddr, &e);
                     if e.as_str() == "close" {
                         debug!("{} {} -> {} {}", self.tag, daddr, saddr, e)
                     } else {
@@ -13,7 +13,7 @@ impl crate::route::OutUdp for super::Out {
         // bind
         let (server_tx, mut server_rx) = crate::route::udp_bind(self.tag.clone(), saddr.clone())?;
 
-        tokio::spawn(async move {
+        crate::route::spawn_in_span(async move {
             match bidirectional_with_timeout!(
                 {
                     // read client
@@ -109,6 +109,7 @@ impl crate::route::OutUdp for super::Out {
             ) {
                 (Err(e), _, _) | (_, _, Err(e)) | (_, Err(e), _) => {
                     let e = e.to_string();
+                    crate::route::report_out_close(&saddr, &e);
                     if e.as_str() == "close" || e.as_str() == "timeout" {
                         debug!("{} {} {}", self.tag, saddr, e)
                     } else {
@@ -11,6 +11,10 @@ pub(crate) const UDP_LEN: usize = 1500;
 pub(crate) const CMD_CONNECT: u8 = 0x01;
 pub(crate) const CMD_UDP_ASSOCIATE: u8 = 0x03;
 
+pub(crate) const REP_SUCCEEDED: u8 = 0x00;
+pub(crate) const REP_GENERAL_FAILURE: u8 = 0x01;
+pub(crate) const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+
 pub(crate) const ATYP_IPV4: u8 = 0x01;
 pub(crate) const ATYP_DOMAIN: u8 = 0x03;
 pub(crate) const ATYP_IPV6: u8 = 0x04;
@@ -49,7 +53,7 @@ pub(crate) fn get_daddr(buf: &[u8]) -> Result<(String, usize), Box<dyn std::erro
             Ok((
                 format!(
                     "{}:{}",
-                    String::from_utf8_lossy(&buf[2..2 + domain_len]).to_string(),
+                    String::from_utf8_lossy(&buf[2..2 + domain_len]),
                     (&buf[2 + domain_len..]).get_u16()
                 ),
                 1 + domain_len,
@@ -91,9 +95,9 @@ pub(crate) fn get_daddr(buf: &[u8]) -> Result<(String, usize), Box<dyn std::erro
 //  o  BND.ADDR       server bound address
 //  o  BND.PORT       server bound port in network octet order
 #[inline]
-pub(crate) fn generate_daddr_buf(daddr: &String) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+pub(crate) fn generate_daddr_buf(daddr: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
     let mut buf = Vec::new();
-    let (addr, port) = split_addr_str(daddr.as_str())?;
+    let (addr, port) = split_addr_str(daddr)?;
     let port = port as u16;
 
     match (addr.as_str(), port).to_socket_addrs() {
@@ -0,0 +1,121 @@
+use log::*;
+use parking_lot::Mutex;
+use socket2::{Domain, SockAddr, Socket, Type};
+use std::{
+    env,
+    ffi::OsStr,
+    os::unix::{ffi::OsStrExt, io::FromRawFd},
+    time::Duration,
+};
+
+// first descriptor passed by socket activation
+const LISTEN_FDS_START: i32 = 3;
+
+lazy_static::lazy_static! {
+    // sockets passed by systemd with their FileDescriptorName, taken by the in with the same tag
+    static ref LISTEN_FDS: Mutex<Vec<(String, Socket)>> = Mutex::new(listen_fds());
+}
+
+// must run before anything forks, LISTEN_PID is checked against our pid
+pub(crate) fn init() {
+    lazy_static::initialize(&LISTEN_FDS);
+}
+
+fn listen_fds() -> Vec<(String, Socket)> {
+    let pid = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|x| x.parse::<u32>().ok());
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|x| x.parse::<i32>().ok());
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+
+    // not for the commands we run
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let count = match (pid, count) {
+        (Some(pid), Some(count)) if pid == std::process::id() => count,
+        _ => return Vec::new(),
+    };
+
+    let names: Vec<&str> = names.split(':').collect();
+    (0..count)
+        .map(|index| {
+            let socket = unsafe { Socket::from_raw_fd(LISTEN_FDS_START + index) };
+            let _ = socket.set_cloexec(true);
+            let name = names.get(index as usize).unwrap_or(&"unknown");
+            (name.to_string(), socket)
+        })
+        .collect()
+}
+
+// the inherited socket named tag with the same type, if any
+pub(crate) fn take_listen_fd(tag: &str, type_: Type) -> Option<Socket> {
+    let mut listen_fds = LISTEN_FDS.lock();
+    let index = listen_fds
+        .iter()
+        .position(|(name, socket)| name == tag && socket.r#type().ok() == Some(type_))?;
+
+    Some(listen_fds.remove(index).1)
+}
+
+// all ins are bound
+pub(crate) fn notify_ready() {
+    for (name, _) in LISTEN_FDS.lock().iter() {
+        warn!("inherited socket {} not used by any in", name);
+    }
+
+    notify("READY=1");
+}
+
+// nothing if not started by systemd with Type=notify
+pub(crate) fn notify(state: &str) {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(s) => s,
+        None => return,
+    };
+
+    if let Err(e) = send_notify(&path, state) {
+        warn!("notify {} {}", state, e);
+    }
+}
+
+fn send_notify(path: &OsStr, state: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = path.as_bytes().to_vec();
+    // abstract socket
+    if path.first() == Some(&b'@') {
+        path[0] = 0;
+    }
+
+    let socket = Socket::new(Domain::UNIX, Type::DGRAM, None)?;
+    socket.send_to(state.as_bytes(), &SockAddr::unix(OsStr::from_bytes(&path))?)?;
+
+    Ok(())
+}
+
+// WATCHDOG=1 at half of WatchdogSec, stops if the runtime hangs
+pub(crate) async fn watchdog() {
+    let usec = match env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|x| x.parse::<u64>().ok())
+    {
+        Some(s) if s > 0 => s,
+        _ => return,
+    };
+    if let Some(pid) = env::var("WATCHDOG_PID")
+        .ok()
+        .and_then(|x| x.parse::<u32>().ok())
+    {
+        if pid != std::process::id() {
+            return;
+        }
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_micros(usec / 2));
+    loop {
+        interval.tick().await;
+        notify("WATCHDOG=1");
+    }
+}
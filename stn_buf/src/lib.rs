@@ -1,14 +1,25 @@
 use std::slice;
 
 pub trait VecBuf {
+    /// The spare capacity after `len`.
+    ///
+    /// # Safety
+    ///
+    /// The bytes are uninitialized, only write them.
     unsafe fn remain_mut(&mut self) -> &mut [u8];
+
+    /// Grows `len` over bytes written through `remain_mut`.
+    ///
+    /// # Safety
+    ///
+    /// `len` bytes after the current length must be initialized and within the capacity.
     unsafe fn add_len(&mut self, len: usize);
 }
 
 impl VecBuf for Vec<u8> {
     unsafe fn remain_mut(&mut self) -> &mut [u8] {
         slice::from_raw_parts_mut(
-            self.as_mut_ptr().add(self.len()) as *mut u8,
+            self.as_mut_ptr().add(self.len()),
             self.capacity() - self.len(),
         )
     }
@@ -16,6 +16,7 @@ pub struct Stream<T> {
     buf: Vec<u8>,
     readable_len: usize, // chunked
     status: Status,
+    version: Option<u8>,
 }
 
 impl<T> Stream<T>
@@ -28,6 +29,7 @@ where
             buf: Vec::with_capacity(BUFLEN),
             readable_len: 0,
             status: Status::ReadHeaders,
+            version: None,
         };
 
         let daddr = me.read_headers().await?;
@@ -53,10 +55,16 @@ where
                 let method = req
                     .method
                     .ok_or(io::Error::new(io::ErrorKind::NotFound, "method not found"))?;
+                self.version = req.version;
                 if method == "CONNECT" {
+                    // answered by response() once the server is connected
+                    if !matches!(self.version, Some(0) | Some(1)) {
+                        Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "invalid http version",
+                        ))?
+                    }
                     self.status = Status::Connect;
-                    let version = req.version.clone();
-                    self.response_connect(version).await?;
                     self.buf.drain(..body_start_index);
                 } else {
                     // if content length
@@ -95,22 +103,22 @@ where
         }
     }
 
-    async fn response_connect(&mut self, version: Option<u8>) -> io::Result<()> {
-        // empty body
-        match version {
-            Some(0) => {
-                self.inner.write_all(b"HTTP/1.0 200 OK\r\n\r\n").await?;
-            }
-            Some(1) => {
-                self.inner.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await?;
-            }
-            _ => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "invalid http version",
-            ))?,
-        }
+    // after new(), 200 to a CONNECT once the server is connected, 502 to any request if not
+    pub async fn response(&mut self, connected: bool) -> io::Result<()> {
+        let status = match (connected, &self.status) {
+            (true, Status::Connect) => "200 OK",
+            (true, _) => return Ok(()),
+            (false, _) => "502 Bad Gateway",
+        };
+        let version = match self.version {
+            Some(0) => "1.0",
+            _ => "1.1",
+        };
 
-        Ok(())
+        // empty body
+        self.inner
+            .write_all(format!("HTTP/{} {}\r\n\r\n", version, status).as_bytes())
+            .await
     }
 
     async fn read_inner(&mut self) -> io::Result<usize> {
@@ -143,14 +151,14 @@ where
                     }
                 }
                 Status::Connect => {
-                    if self.buf.len() == 0 {
+                    if self.buf.is_empty() {
                         self.read_inner().await?;
                     }
 
                     self.readable_len = self.buf.len();
                 }
                 Status::LeftContentLength(content_length) => {
-                    if self.buf.len() == 0 {
+                    if self.buf.is_empty() {
                         self.read_inner().await?;
                     }
 
@@ -236,7 +244,9 @@ enum Status {
     Chunked,
 }
 
+// a manual proxy on port 11 which never returns, run with --ignored
 #[tokio::test]
+#[ignore]
 async fn t1() -> Result<(), Box<dyn std::error::Error>> {
     let tcp_listener = tokio::net::TcpListener::bind("0.0.0.0:11").await?;
 
@@ -248,9 +258,10 @@ async fn t1() -> Result<(), Box<dyn std::error::Error>> {
         println!("{}", addr);
 
         let mut server = tokio::net::TcpStream::connect(addr).await?;
+        client.response(true).await?;
         let r = tokio::io::copy_bidirectional(&mut client, &mut server).await?;
         println!("{:?}", r);
 
-        println!("");
+        println!();
     }
 }
@@ -4,12 +4,12 @@ use std::io;
 pub(crate) fn get_content_length(headers: &[Header]) -> io::Result<usize> {
     for i in headers {
         if i.name == "Content-Length" {
-            return Ok(String::from_utf8_lossy(i.value)
+            return String::from_utf8_lossy(i.value)
                 .parse()
                 .or(Err(io::Error::new(
                     io::ErrorKind::InvalidInput,
                     "invalid Content-Length value",
-                )))?);
+                )));
         }
     }
 
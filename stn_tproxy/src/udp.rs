@@ -28,48 +28,54 @@ impl UdpSocket {
             Err(io::Error::last_os_error())?
         }
 
+        // ipv6_only
+        if addr.ss_family as libc::c_int == libc::AF_INET6 && !ipv6_only {
+            set_ipv6_only(fd, false)?;
+        }
+
         // set tproxy
-        let enable = 1;
-        let set_ipv4 = || -> io::Result<()> {
-            enable_transparent(fd, true, false)?;
-            if unsafe {
-                libc::setsockopt(
-                    fd,
-                    libc::SOL_IP,
-                    libc::IP_RECVORIGDSTADDR,
-                    &enable as *const _ as _,
-                    mem::size_of_val(&enable) as _,
-                ) == -1
-            } {
-                Err(io::Error::last_os_error())?
-            }
-            Ok(())
-        };
-        if addr.ss_family as libc::c_int == libc::AF_INET {
-            set_ipv4()?;
-        } else {
-            enable_transparent(fd, false, true)?;
+        set_tproxy(fd, addr.ss_family as _, ipv6_only)?;
+
+        // bind
+        if unsafe { libc::bind(fd, &addr as *const _ as _, mem::size_of_val(&addr) as _) == -1 } {
+            Err(io::Error::last_os_error())?
+        }
+
+        Ok(Self {
+            udp_listener: AsyncFd::new(fd)?,
+        })
+    }
+
+    // a bound socket passed by socket activation, tproxy is set on it like bind
+    pub fn from_fd(fd: i32) -> io::Result<UdpSocket> {
+        let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut len = mem::size_of_val(&addr) as libc::socklen_t;
+        if unsafe { libc::getsockname(fd, &mut addr as *mut _ as _, &mut len) == -1 } {
+            Err(io::Error::last_os_error())?
+        }
+
+        // IPV6_V6ONLY can't change once bound
+        let mut ipv6_only: libc::c_int = 0;
+        if addr.ss_family as libc::c_int == libc::AF_INET6 {
+            let mut len = mem::size_of_val(&ipv6_only) as libc::socklen_t;
             if unsafe {
-                libc::setsockopt(
+                libc::getsockopt(
                     fd,
                     libc::SOL_IPV6,
-                    libc::IPV6_RECVORIGDSTADDR,
-                    &enable as *const _ as _,
-                    mem::size_of_val(&enable) as _,
+                    libc::IPV6_V6ONLY,
+                    &mut ipv6_only as *mut _ as _,
+                    &mut len,
                 ) == -1
             } {
                 Err(io::Error::last_os_error())?
             }
-
-            // ipv6_only
-            if !ipv6_only {
-                set_ipv6_only(fd, false)?;
-                set_ipv4()?;
-            }
         }
+        set_tproxy(fd, addr.ss_family as _, ipv6_only != 0)?;
 
-        // bind
-        if unsafe { libc::bind(fd, &addr as *const _ as _, mem::size_of_val(&addr) as _) == -1 } {
+        // nonblocking
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } == -1
+        {
             Err(io::Error::last_os_error())?
         }
 
@@ -161,6 +167,48 @@ impl UdpSocket {
     }
 }
 
+// IP_TRANSPARENT and IP_RECVORIGDSTADDR, for ipv4 too on a dual stack ipv6 socket
+fn set_tproxy(fd: i32, family: libc::c_int, ipv6_only: bool) -> io::Result<()> {
+    let enable = 1;
+    let set_ipv4 = || -> io::Result<()> {
+        enable_transparent(fd, true, false)?;
+        if unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_IP,
+                libc::IP_RECVORIGDSTADDR,
+                &enable as *const _ as _,
+                mem::size_of_val(&enable) as _,
+            ) == -1
+        } {
+            Err(io::Error::last_os_error())?
+        }
+        Ok(())
+    };
+    if family == libc::AF_INET {
+        set_ipv4()?;
+    } else {
+        enable_transparent(fd, false, true)?;
+        if unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_IPV6,
+                libc::IPV6_RECVORIGDSTADDR,
+                &enable as *const _ as _,
+                mem::size_of_val(&enable) as _,
+            ) == -1
+        } {
+            Err(io::Error::last_os_error())?
+        }
+
+        if !ipv6_only {
+            set_ipv4()?;
+        }
+    }
+
+    Ok(())
+}
+
 fn tproxy_recv_from(fd: i32, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, SocketAddr)> {
     let mut control_buf = [0u8; mem::size_of::<libc::sockaddr_storage>()];
     let mut saddr: libc::sockaddr_storage = unsafe { mem::zeroed() };
@@ -191,11 +239,12 @@ fn tproxy_recv_from(fd: i32, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, S
 }
 
 fn parse_tproxy_msghdr(msghdr: &libc::msghdr) -> io::Result<libc::sockaddr_storage> {
-    let mut cmsghdr = unsafe { &*libc::CMSG_FIRSTHDR(msghdr) };
+    // a raw pointer, CMSG_NXTHDR returns null after the last one
+    let mut cmsghdr = unsafe { libc::CMSG_FIRSTHDR(msghdr) };
     let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
 
-    while !(cmsghdr as *const libc::cmsghdr).is_null() {
-        match (cmsghdr.cmsg_level, cmsghdr.cmsg_type) {
+    while !cmsghdr.is_null() {
+        match unsafe { ((*cmsghdr).cmsg_level, (*cmsghdr).cmsg_type) } {
             (libc::SOL_IP, libc::IP_RECVORIGDSTADDR) => unsafe {
                 ptr::copy(
                     libc::CMSG_DATA(cmsghdr),
@@ -211,7 +260,7 @@ fn parse_tproxy_msghdr(msghdr: &libc::msghdr) -> io::Result<libc::sockaddr_stora
                 );
             },
             _ => {
-                cmsghdr = unsafe { &*libc::CMSG_NXTHDR(msghdr, cmsghdr) };
+                cmsghdr = unsafe { libc::CMSG_NXTHDR(msghdr, cmsghdr) };
                 continue;
             }
         }
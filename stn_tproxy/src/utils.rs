@@ -14,7 +14,7 @@ pub fn enable_transparent(fd: i32, ipv4: bool, ipv6: bool) -> io::Result<()> {
     let enable = 1;
 
     // check input
-    if ipv4 == false && ipv6 == false {
+    if !ipv4 && !ipv6 {
         Err(io::Error::new(
             io::ErrorKind::InvalidInput,
             "ipv4 == false && ipv6 == false",
@@ -60,7 +60,7 @@ pub fn set_ipv6_only(fd: i32, ipv6_only: bool) -> io::Result<()> {
             mem::size_of::<i32>() as _,
         ) == -1
     } {
-        Err(io::Error::last_os_error()).unwrap()
+        Err(io::Error::last_os_error())?
     }
 
     Ok(())
@@ -76,7 +76,7 @@ pub fn set_reuse_address(fd: i32, reuse_address: bool) -> io::Result<()> {
             mem::size_of::<i32>() as _,
         ) == -1
     } {
-        Err(io::Error::last_os_error()).unwrap()
+        Err(io::Error::last_os_error())?
     }
 
     Ok(())
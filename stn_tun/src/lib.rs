@@ -5,4 +5,3 @@ mod udp;
 
 pub(crate) use tcp::*;
 pub use tun::*;
-pub use udp::*;
@@ -18,7 +18,6 @@ impl super::Tun {
                     .ok_or(io::Error::new(io::ErrorKind::InvalidData, "invalid saddr"))?,
             )
             .ok_or(io::Error::new(io::ErrorKind::NotFound, "daddr not found"))?
-            .clone()
             .0)
     }
 
@@ -75,7 +74,7 @@ impl super::Tun {
 
                     tcp_header.source_port = saddr.port();
                     tcp_header.destination_port = fake_daddr.port();
-                    tcp_header.checksum = tcp_header.calc_checksum_ipv4(&ip_header, payload)?;
+                    tcp_header.checksum = tcp_header.calc_checksum_ipv4(ip_header, payload)?;
 
                     (status, saddr)
                 } else if let Some((SocketAddr::V4(origin_daddr), status)) =
@@ -87,7 +86,7 @@ impl super::Tun {
 
                     tcp_header.source_port = origin_daddr.port();
                     tcp_header.destination_port = daddr.port();
-                    tcp_header.checksum = tcp_header.calc_checksum_ipv4(&ip_header, payload)?;
+                    tcp_header.checksum = tcp_header.calc_checksum_ipv4(ip_header, payload)?;
 
                     (status, saddr)
                 } else {
@@ -109,7 +108,7 @@ impl super::Tun {
 
                     tcp_header.source_port = saddr.port();
                     tcp_header.destination_port = fake_daddr.port();
-                    tcp_header.checksum = tcp_header.calc_checksum_ipv6(&ip_header, payload)?;
+                    tcp_header.checksum = tcp_header.calc_checksum_ipv6(ip_header, payload)?;
 
                     (status, saddr)
                 } else if let Some((SocketAddr::V6(origin_daddr), status)) =
@@ -120,7 +119,7 @@ impl super::Tun {
 
                     tcp_header.source_port = origin_daddr.port();
                     tcp_header.destination_port = daddr.port();
-                    tcp_header.checksum = tcp_header.calc_checksum_ipv6(&ip_header, payload)?;
+                    tcp_header.checksum = tcp_header.calc_checksum_ipv6(ip_header, payload)?;
 
                     (status, saddr)
                 } else {
@@ -164,7 +163,9 @@ pub(crate) enum TcpStatus {
 // cargo test --package stn_tun tcp::t1 -- --nocapture
 //
 // curl --interface tun123 1.2.3.4
+// manual, needs a tun device, run with --ignored
 #[tokio::test]
+#[ignore]
 async fn t1() {
     use tokio::io::AsyncReadExt;
 
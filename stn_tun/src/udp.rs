@@ -73,7 +73,9 @@ impl super::Tun {
 // cargo test --package stn_tun udp::t1 -- --nocapture
 //
 // tcpdump -i tun123 -vvnX
+// manual, needs a tun device, run with --ignored
 #[tokio::test]
+#[ignore]
 async fn t1() {
     use tokio::net::UdpSocket;
 
@@ -99,7 +101,9 @@ async fn t1() {
 // ip route add default dev tun123 table 123
 // ip rule add to 1.2.3.4 lookup 123
 // dig @1.2.3.4 a.com
+// manual, needs a tun device, run with --ignored
 #[tokio::test]
+#[ignore]
 async fn t2() {
     let (_tun, mut tun_udp_rx) = crate::Tun::new("tun123", None, None, None, None)
         .await